@@ -0,0 +1,352 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `issue-bot list`, `show` and the other commands for inspecting and managing tracked issues
+//! without opening the database by hand.
+
+use super::*;
+use serde_json::json;
+
+static USAGE: &str = "Usage: issue_bot [list [--open | --closed] [--address <address>] [--json] | show <number> [--json] | subscribe <number> [<address>] | unsubscribe <number> [<address>] | reset-token <number> [<address>] | forget <number> | resend-confirmation <number> [<address>]]";
+
+/// Whether the `address` stored for a submitter or subscriber, eg. `Name <user@example.com>`, is
+/// `address`.
+fn same_address(stored: &str, address: &str) -> bool {
+    let (stored, address) = (stored.to_lowercase(), address.to_lowercase());
+    stored == address || stored.ends_with(&format!("<{}>", address))
+}
+
+fn state(issue: &Issue) -> &'static str {
+    match issue.closed {
+        Some(true) => "closed",
+        Some(false) => "open",
+        None => "unknown",
+    }
+}
+
+fn find_issue(conn: &Connection, id: &str) -> Result<Issue> {
+    let id = id.parse::<i64>()?;
+    db::issue(conn, id)?.ok_or_else(|| Error::new(format!("Issue {} is not tracked.", id)))
+}
+
+/// The password of `address` for `issue`: the submitter's if `address` is `None`, and `None` if
+/// `address` has no confirmed subscription.
+fn find_password(
+    conn: &Connection,
+    issue: &Issue,
+    address: Option<&str>,
+) -> Result<Option<Password>> {
+    match address {
+        None => Ok(Some(issue.password)),
+        Some(address) if same_address(&issue.submitter.to_string(), address) => {
+            Ok(Some(issue.password))
+        }
+        Some(address) => Ok(db::subscriptions(conn, issue.id)?
+            .into_iter()
+            .find(|s| s.confirmed && same_address(&s.address, address))
+            .map(|s| s.password)),
+    }
+}
+
+/// Like [`find_password`], but an address without a confirmed subscription is an error.
+fn password(conn: &Connection, issue: &Issue, address: Option<&str>) -> Result<Password> {
+    find_password(conn, issue, address)?.ok_or_else(|| {
+        Error::new(format!(
+            "{} has no confirmed subscription to issue {}.",
+            address.unwrap_or_default(),
+            issue.id
+        ))
+    })
+}
+
+fn list(conn: &Connection, args: &[&str]) -> Result<()> {
+    let (mut closed, mut address, mut as_json) = (None, None, false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--open" => closed = Some(false),
+            "--closed" => closed = Some(true),
+            "--address" => address = Some(*args.next().ok_or_else(|| Error::new(USAGE))?),
+            "--json" => as_json = true,
+            _ => return Err(Error::new(USAGE)),
+        }
+    }
+    let mut rows = vec![];
+    for issue in db::issues(conn)? {
+        if closed.is_some() && issue.closed.unwrap_or(false) != closed.unwrap_or_default() {
+            continue;
+        }
+        let subscriptions = db::subscriptions(conn, issue.id)?;
+        if let Some(address) = address {
+            if !same_address(&issue.submitter.to_string(), address)
+                && !subscriptions
+                    .iter()
+                    .any(|s| same_address(&s.address, address))
+            {
+                continue;
+            }
+        }
+        let subscribers = subscriptions
+            .iter()
+            .filter(|s| s.confirmed && s.subscribed)
+            .count();
+        rows.push((issue, subscribers));
+    }
+    if as_json {
+        let rows = rows
+            .iter()
+            .map(|(issue, subscribers)| {
+                json!({
+                    "number": issue.id,
+                    "title": issue.title,
+                    "state": state(issue),
+                    "submitter": issue.submitter.to_string(),
+                    "anonymous": issue.anonymous,
+                    "subscribers": subscribers,
+                    "last_update": issue.last_update,
+                })
+            })
+            .collect::<Vec<serde_json::Value>>();
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+        return Ok(());
+    }
+    if rows.is_empty() {
+        println!("No tracked issues.");
+    }
+    for (issue, subscribers) in rows {
+        println!(
+            "{id}\t{state}\t{title}\n\tSubmitter: {submitter}{anonymous}\t{subscribers} subscriber(s)\tLast update: {last_update}",
+            id = issue.id,
+            state = state(&issue),
+            title = issue.title,
+            submitter = issue.submitter,
+            anonymous = if issue.anonymous { " (anonymous)" } else { "" },
+            subscribers = subscribers,
+            last_update = issue.last_update,
+        );
+    }
+    Ok(())
+}
+
+fn show(conn: &Connection, conf: &Configuration, id: &str, as_json: bool) -> Result<()> {
+    let issue = find_issue(conn, id)?;
+    let subscriptions = db::subscriptions(conn, issue.id)?;
+    let pending: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM pending_update WHERE issue_id = ?",
+        [issue.id],
+        |row| row.get(0),
+    )?;
+    if as_json {
+        let subscriptions = subscriptions
+            .iter()
+            .map(|s| {
+                json!({
+                    "address": s.address,
+                    "confirmed": s.confirmed,
+                    "subscribed": s.subscribed,
+                    "muted": s.muted.iter().map(|k| k.as_str()).collect::<Vec<&str>>(),
+                    "time_created": s.time_created,
+                })
+            })
+            .collect::<Vec<serde_json::Value>>();
+        let issue = json!({
+            "number": issue.id,
+            "title": issue.title,
+            "url": templates::issue_url(issue.id, conf),
+            "state": state(&issue),
+            "submitter": {
+                "address": issue.submitter.to_string(),
+                "anonymous": issue.anonymous,
+                "subscribed": issue.subscribed,
+                "muted": issue.muted.iter().map(|k| k.as_str()).collect::<Vec<&str>>(),
+            },
+            "time_created": issue.time_created,
+            "last_update": issue.last_update,
+            "subscriptions": subscriptions,
+            "pending_updates": pending,
+        });
+        println!("{}", serde_json::to_string_pretty(&issue)?);
+        return Ok(());
+    }
+    let muted = |kinds: &[EventKind]| {
+        if kinds.is_empty() {
+            String::new()
+        } else {
+            format!("\tmuted: {}", EventKind::join_list(kinds))
+        }
+    };
+    println!("Issue {}: {}", issue.id, issue.title);
+    println!("URL: {}", templates::issue_url(issue.id, conf));
+    println!("State: {}", state(&issue));
+    println!("Created: {}", issue.time_created);
+    println!("Last update: {}", issue.last_update);
+    println!(
+        "Submitter: {}{}\t{}{}",
+        issue.submitter,
+        if issue.anonymous { " (anonymous)" } else { "" },
+        if issue.subscribed {
+            "subscribed"
+        } else {
+            "unsubscribed"
+        },
+        muted(&issue.muted)
+    );
+    println!("Subscriptions: {}", subscriptions.len());
+    for s in &subscriptions {
+        println!(
+            "\t{}\t{}\t{}\tsince {}{}",
+            s.address,
+            if s.confirmed {
+                "confirmed"
+            } else {
+                "unconfirmed"
+            },
+            if s.subscribed {
+                "subscribed"
+            } else {
+                "unsubscribed"
+            },
+            s.time_created,
+            muted(&s.muted)
+        );
+    }
+    println!("Pending digest updates: {}", pending);
+    Ok(())
+}
+
+/// Subscribe the submitter, or `address`. An address without a confirmed subscription is sent a
+/// confirmation request: nobody is subscribed without their consent.
+fn subscribe(
+    conn: &Connection,
+    conf: &Configuration,
+    id: &str,
+    address: Option<&str>,
+) -> Result<()> {
+    let issue = find_issue(conn, id)?;
+    match (find_password(conn, &issue, address)?, address) {
+        (Some(password), _) => {
+            api::change_subscription(conn, password, true)?;
+            println!("Subscribed to issue {}.", issue.id);
+        }
+        (None, Some(address)) => {
+            request_subscription(
+                conn,
+                conf,
+                issue.id,
+                Address::new(None, address.to_string()),
+                None,
+                None,
+            )?;
+            println!("Sent a confirmation request to {}.", address);
+        }
+        (None, None) => unreachable!("the submitter always has a password"),
+    }
+    Ok(())
+}
+
+fn unsubscribe(conn: &Connection, id: &str, address: Option<&str>) -> Result<()> {
+    let issue = find_issue(conn, id)?;
+    api::change_subscription(conn, password(conn, &issue, address)?, false)?;
+    println!("Unsubscribed from issue {}.", issue.id);
+    Ok(())
+}
+
+/// Give the submitter, or `address`, a new password, eg. after the old one leaked. Pending digest
+/// updates are changed to link to the new one.
+fn reset_token(conn: &Connection, id: &str, address: Option<&str>) -> Result<()> {
+    let issue = find_issue(conn, id)?;
+    let old = password(conn, &issue, address)?;
+    let new = Uuid::new_v4();
+    let tx = db::write_transaction(conn)?;
+    for table in ["issue", "subscription", "pending_update"] {
+        tx.execute(
+            &format!("UPDATE {} SET password = ?1 WHERE password = ?2", table),
+            [new.as_bytes().to_vec(), old.as_bytes().to_vec()],
+        )?;
+    }
+    tx.commit()?;
+    println!("The new password is {}.", new);
+    Ok(())
+}
+
+/// Stop tracking an issue: delete it from the database, but not from Gitea.
+fn forget(conn: &Connection, id: &str) -> Result<()> {
+    let issue = find_issue(conn, id)?;
+    db::forget(conn, issue.id)?;
+    info!("Forgot issue {}.", issue.id);
+    println!(
+        "Issue {} deleted from the database. It is still in Gitea.",
+        issue.id
+    );
+    Ok(())
+}
+
+/// Send the subscription request of every unconfirmed subscription to the issue again, or only
+/// that of `address`.
+fn resend_confirmation(
+    conn: &Connection,
+    conf: &Configuration,
+    id: &str,
+    address: Option<&str>,
+) -> Result<()> {
+    let issue = find_issue(conn, id)?;
+    let unconfirmed = db::subscriptions(conn, issue.id)?
+        .into_iter()
+        .filter(|s| !s.confirmed && address.is_none_or(|a| same_address(&s.address, a)))
+        .collect::<Vec<db::Subscription>>();
+    if unconfirmed.is_empty() {
+        return Err(Error::new(format!(
+            "No unconfirmed subscription to issue {}.",
+            issue.id
+        )));
+    }
+    for s in unconfirmed {
+        request_subscription(
+            conn,
+            conf,
+            issue.id,
+            Address::new(None, s.address.clone()),
+            None,
+            None,
+        )?;
+        println!("Sent a confirmation request to {}.", s.address);
+    }
+    Ok(())
+}
+
+pub fn run(conn: &Connection, conf: &Configuration, args: &[&str]) -> Result<()> {
+    let read_only = matches!(args.first(), Some(&"list") | Some(&"show"));
+    if conf.dry_run && !read_only {
+        println!("DRY_RUN: NOT running `{}`.", args.join(" "));
+        return Ok(());
+    }
+    match args {
+        ["list", rest @ ..] => list(conn, rest),
+        ["show", id] => show(conn, conf, id, false),
+        ["show", id, "--json"] | ["show", "--json", id] => show(conn, conf, id, true),
+        ["subscribe", id] => subscribe(conn, conf, id, None),
+        ["subscribe", id, address] => subscribe(conn, conf, id, Some(address)),
+        ["unsubscribe", id] => unsubscribe(conn, id, None),
+        ["unsubscribe", id, address] => unsubscribe(conn, id, Some(address)),
+        ["reset-token", id] => reset_token(conn, id, None),
+        ["reset-token", id, address] => reset_token(conn, id, Some(address)),
+        // `untrack` is an alias, to tell it apart from the `+forget` of reporters.
+        ["forget" | "untrack", id] => forget(conn, id),
+        ["resend-confirmation", id] => resend_confirmation(conn, conf, id, None),
+        ["resend-confirmation", id, address] => resend_confirmation(conn, conf, id, Some(address)),
+        _ => Err(Error::new(USAGE)),
+    }
+}
@@ -32,8 +32,282 @@ macro_rules! gitea_api_mismatch {
 
 static ISSUES_BASE_URL: &str = "{base_url}/api/v1/repos/{repo}/issues";
 static ISSUES_COMMENTS_URL: &str = "{base_url}/api/v1/repos/{repo}/issues/{index}/comments";
+static REPO_COMMENTS_URL: &str = "{base_url}/api/v1/repos/{repo}/issues/comments";
+static ISSUES_TIMELINE_URL: &str = "{base_url}/api/v1/repos/{repo}/issues/{index}/timeline";
+static REPO_URL: &str = "{base_url}/api/v1/repos/{repo}";
+static USER_URL: &str = "{base_url}/api/v1/user";
+static VERSION_URL: &str = "{base_url}/api/v1/version";
 
+/// Items requested per page from list endpoints. Gitea clamps this to its `MAX_RESPONSE_ITEMS`
+/// setting, so we never assume a page is the whole list.
+const PAGE_LIMIT: usize = 50;
+
+/// Upper bound for a server-provided `Retry-After`, so a misbehaving server can't park the MTA
+/// pipe for hours.
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
 use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type JsonMap = serde_json::map::Map<String, serde_json::Value>;
+
+static CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+/// Earliest time the next request may be sent, see [`throttle`].
+static NEXT_REQUEST: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Wait for our turn under `conf.http.max_requests_per_second`, shared by every thread.
+fn throttle(conf: &Configuration) {
+    // The configuration is validated when it's loaded.
+    let interval = match conf.http.request_interval() {
+        Ok(Some(interval)) => interval,
+        _ => return,
+    };
+    let wait = {
+        let mut next_request = NEXT_REQUEST.lock().unwrap();
+        let now = Instant::now();
+        let slot = next_request.map(|n| n.max(now)).unwrap_or(now);
+        *next_request = Some(slot + interval);
+        slot - now
+    };
+    if !wait.is_zero() {
+        std::thread::sleep(wait);
+    }
+}
+
+/// Returns the process-wide HTTP client, building it from `conf.http` on first use.
+pub fn client(conf: &Configuration) -> Result<Client> {
+    let mut client = CLIENT.lock().unwrap();
+    if let Some(ref client) = *client {
+        return Ok(client.clone());
+    }
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(conf.http.connect_timeout))
+        .timeout(Duration::from_secs(conf.http.timeout));
+    if let Some(ref proxy) = conf.http.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    if let Some(ref ca_bundle) = conf.http.ca_bundle {
+        let pem = std::fs::read(ca_bundle)?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    let new_client = builder.build()?;
+    *client = Some(new_client.clone());
+    Ok(new_client)
+}
+
+/// Drop the shared client so that the next request builds one from the current configuration.
+pub fn reset_client() {
+    *CLIENT.lock().unwrap() = None;
+}
+
+/// How long the server asked us to wait at `now`, either as delay-seconds or as an HTTP-date, at
+/// most [`MAX_RETRY_WAIT`].
+fn retry_after(headers: &HeaderMap, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    let wait = if let Ok(seconds) = value.parse::<u64>() {
+        Duration::from_secs(seconds)
+    } else {
+        let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+        (date.with_timezone(&chrono::Utc) - now)
+            .to_std()
+            .unwrap_or_default()
+    };
+    Some(wait.min(MAX_RETRY_WAIT))
+}
+
+/// How long to wait before retrying after `attempt` failed attempts: what the server asked for
+/// in `retry_after`, or else `backoff` milliseconds doubled after every attempt.
+fn retry_delay(retry_after: Option<Duration>, attempt: u32, backoff: u64) -> Duration {
+    retry_after.unwrap_or_else(|| {
+        Duration::from_millis(backoff).saturating_mul(2u32.saturating_pow(attempt))
+    })
+}
+
+/// Send `request`, retrying with exponential backoff.
+///
+/// Rate limiting (429) and connection failures are retried for every request since the server
+/// never acted on them. Timeouts and 5xx responses are only retried when `idempotent` is set,
+/// because a POST may have gone through even though we didn't see the response.
+fn send(request: RequestBuilder, idempotent: bool, conf: &Configuration) -> Result<Response> {
+    let mut request = request;
+    let mut attempt = 0;
+    loop {
+        let next = if attempt < conf.http.retries {
+            request.try_clone()
+        } else {
+            None
+        };
+        throttle(conf);
+        let Some(next) = next else {
+            return Ok(request.send()?);
+        };
+        let asked = match request.send() {
+            Ok(response)
+                if response.status() == StatusCode::TOO_MANY_REQUESTS
+                    || (idempotent && response.status() == StatusCode::SERVICE_UNAVAILABLE) =>
+            {
+                log::warn!("{} responded with {}.", response.url(), response.status());
+                retry_after(response.headers(), chrono::Utc::now())
+            }
+            Ok(response) if idempotent && response.status().is_server_error() => {
+                log::warn!("{} responded with {}.", response.url(), response.status());
+                None
+            }
+            Ok(response) => return Ok(response),
+            Err(err) if err.is_connect() || (idempotent && err.is_timeout()) => {
+                log::warn!("HTTP request failed: {}", err);
+                None
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let wait = retry_delay(asked, attempt, conf.http.backoff);
+        attempt += 1;
+        log::warn!(
+            "Retrying in {:?} (attempt {} of {}).",
+            wait,
+            attempt,
+            conf.http.retries
+        );
+        std::thread::sleep(wait);
+        request = next;
+    }
+}
+
+/// Turn non-2xx responses into an error instead of letting serde choke on an error page.
+fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().clone();
+    let body = response.text().unwrap_or_default();
+    log::error!(
+        "Gitea API request to {} failed with {}: {}",
+        url,
+        status,
+        body
+    );
+    Err(Error::new(format!(
+        "Gitea API request failed with HTTP status {}.",
+        status
+    )))
+}
+
+/// Returns the `rel="next"` target of a `Link` response header, if any.
+fn next_link(headers: &HeaderMap) -> Option<String> {
+    let link = headers.get(reqwest::header::LINK)?.to_str().ok()?;
+    link.split(',').find_map(|part| {
+        let (target, params) = part.split_once(';')?;
+        if params.split(';').any(|p| p.trim() == r#"rel="next""#) {
+            Some(
+                target
+                    .trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string(),
+            )
+        } else {
+            None
+        }
+    })
+}
+
+/// What [`get_all_pages`] fetches after a page.
+#[derive(Debug, PartialEq, Eq)]
+enum NextPage {
+    /// The `rel="next"` target of the `Link` header.
+    Link(String),
+    /// The following `page`.
+    Following,
+    Done,
+}
+
+/// Decide whether there is another page after one of `len` items with response `headers`,
+/// `collected` items so far including these, and `limit` items in a full page.
+fn next_page(headers: &HeaderMap, len: usize, collected: usize, limit: usize) -> NextPage {
+    if len == 0 {
+        return NextPage::Done;
+    }
+    if let Some(url) = next_link(headers) {
+        return NextPage::Link(url);
+    }
+    // Gitea only sends `Link` when there are several pages, so one without `rel="next"` is sent
+    // with the last page.
+    if headers.contains_key(reqwest::header::LINK) {
+        return NextPage::Done;
+    }
+    let total_count: Option<usize> = headers
+        .get("X-Total-Count")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    if total_count.map(|t| collected < t).unwrap_or(len >= limit) {
+        NextPage::Following
+    } else {
+        NextPage::Done
+    }
+}
+
+fn get_object(url: &str, conf: &Configuration) -> Result<JsonMap> {
+    let client = client(conf)?;
+    let response = check_status(send(
+        client
+            .get(url)
+            .header("Authorization", format!("token {}", &conf.auth_token)),
+        true,
+        conf,
+    )?)?;
+    Ok(serde_json::from_str(&response.text()?)?)
+}
+
+/// GET every page of a Gitea list endpoint.
+///
+/// Follows the `Link` header when the server sends one, until it has no `rel="next"`, and
+/// otherwise keeps asking for the next `page` until `X-Total-Count` items have been collected or
+/// a page comes back empty. Without either header, eg. behind a proxy that strips them, a page
+/// shorter than the first one is the last one: Gitea may send fewer than `PAGE_LIMIT` items per
+/// page.
+fn get_all_pages(url: &str, query: &[(&str, &str)], conf: &Configuration) -> Result<Vec<JsonMap>> {
+    let client = client(conf)?;
+    let limit = PAGE_LIMIT.to_string();
+    let mut ret = vec![];
+    let mut page: usize = 1;
+    let mut page_size = None;
+    let mut next_url: Option<String> = None;
+    loop {
+        let request = if let Some(next_url) = next_url.take() {
+            client.get(&next_url)
+        } else {
+            client.get(url).query(query).query(&[
+                ("page", page.to_string().as_str()),
+                ("limit", limit.as_str()),
+            ])
+        };
+        let response = check_status(send(
+            request.header("Authorization", format!("token {}", &conf.auth_token)),
+            true,
+            conf,
+        )?)?;
+        let headers = response.headers().clone();
+        let items: Vec<JsonMap> = serde_json::from_str(&response.text()?)?;
+        let len = items.len();
+        let page_size = *page_size.get_or_insert(len);
+        ret.extend(items);
+        match next_page(&headers, len, ret.len(), page_size) {
+            NextPage::Link(url) => next_url = Some(url),
+            NextPage::Following => page += 1,
+            NextPage::Done => break,
+        }
+    }
+    Ok(ret)
+}
 
 #[derive(Serialize, Default)]
 struct CreateIssueOption {
@@ -65,17 +339,20 @@ pub fn new_issue(
         ),
         ..CreateIssueOption::default()
     };
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post(
-            &ISSUES_BASE_URL
-                .replace("{base_url}", &conf.base_url)
-                .replace("{repo}", &conf.repo),
-        )
-        .header("Authorization", format!("token {}", &conf.auth_token))
-        .json(&issue)
-        .send()?
-        .text()?;
+    let client = client(conf)?;
+    let res = check_status(send(
+        client
+            .post(
+                &ISSUES_BASE_URL
+                    .replace("{base_url}", &conf.base_url)
+                    .replace("{repo}", &conf.repo),
+            )
+            .header("Authorization", format!("token {}", &conf.auth_token))
+            .json(&issue),
+        false,
+        conf,
+    )?)?
+    .text()?;
 
     let map: serde_json::map::Map<String, serde_json::Value> = serde_json::from_str(&res)?;
     let issue = Issue {
@@ -87,10 +364,12 @@ pub fn new_issue(
         subscribed: true,
         title: issue.title,
         last_update: gitea_api_mismatch!(map["created_at"].as_str()).to_string(),
+        muted: vec![],
+        closed: Some(false),
     };
     conn.execute(
-        "INSERT INTO issue (id, submitter, password, time_created, anonymous, subscribed, title, last_update)
-                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        "INSERT INTO issue (id, submitter, password, time_created, anonymous, subscribed, title, last_update, state)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 'open')",
         &[
             &issue.id,
             &issue.submitter.to_string() as &dyn ToSql,
@@ -117,40 +396,33 @@ pub fn new_reply(
     submitter: Address,
     conf: &Configuration,
 ) -> Result<(String, i64, bool)> {
-    let mut stmt =
-        conn.prepare("SELECT id, title, subscribed, anonymous FROM issue WHERE password = ?")?;
-    let mut results = stmt
-        .query_map([password.as_bytes().to_vec()], |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
-        })?
-        .collect::<std::result::Result<Vec<(i64, String, bool, bool)>, _>>()?;
-    if results.is_empty() {
-        return Err(Error::new("Not found".to_string()));
-    }
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .post(
-            &ISSUES_COMMENTS_URL
-                .replace("{base_url}", &conf.base_url)
-                .replace("{repo}", &conf.repo)
-                .replace("{index}", &results[0].0.to_string()),
-        )
-        .header("Authorization", format!("token {}", &conf.auth_token))
-        .json(&CreateIssueCommentOption {
-            body: format!(
-                "{} replies:\n\n{}",
-                if results[0].3 {
-                    "Anonymous".to_string()
-                } else {
-                    submitter.to_string()
-                },
-                body
-            ),
-        })
-        .send()?;
+    let token = db::find_token(conn, password)?;
+    let client = client(conf)?;
+    let response = send(
+        client
+            .post(
+                &ISSUES_COMMENTS_URL
+                    .replace("{base_url}", &conf.base_url)
+                    .replace("{repo}", &conf.repo)
+                    .replace("{index}", &token.issue_id.to_string()),
+            )
+            .header("Authorization", format!("token {}", &conf.auth_token))
+            .json(&CreateIssueCommentOption {
+                body: format!(
+                    "{} replies:\n\n{}",
+                    if token.anonymous {
+                        "Anonymous".to_string()
+                    } else {
+                        submitter.to_string()
+                    },
+                    body
+                ),
+            }),
+        false,
+        conf,
+    )?;
     if response.status().is_success() {
-        let (issue_id, title, is_subscribed, _) = results.remove(0);
-        Ok((title, issue_id, is_subscribed))
+        Ok((token.title, token.issue_id, token.subscribed))
     } else {
         eprintln!(
             "New reply could not be created: {:?}\npassword: {}\nsubmitter: {}\nbody: {}",
@@ -167,7 +439,10 @@ pub fn new_reply(
 
 #[derive(Serialize, Default)]
 struct EditIssueOption {
-    state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
 }
 
 pub fn close(
@@ -175,35 +450,34 @@ pub fn close(
     password: Password,
     conf: &Configuration,
 ) -> Result<(String, i64, bool)> {
-    let mut stmt = conn.prepare("SELECT id, title, subscribed FROM issue WHERE password = ?")?;
-    let mut results = stmt
-        .query_map([password.as_bytes().to_vec()], |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-        })?
-        .collect::<std::result::Result<Vec<(i64, String, bool)>, _>>()?;
-    if results.is_empty() {
-        return Err(Error::new("Not found".to_string()));
+    let token = db::find_token(conn, password)?;
+    if token.subscription.is_some() {
+        return Err(Error::new("Only the submitter of an issue can close it."));
     }
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .patch(&format!(
-            "{}/{}",
-            ISSUES_BASE_URL
-                .replace("{base_url}", &conf.base_url)
-                .replace("{repo}", &conf.repo),
-            &results[0].0.to_string()
-        ))
-        .header("Authorization", format!("token {}", &conf.auth_token))
-        .json(&EditIssueOption {
-            state: "closed".to_string(),
-        })
-        .send()?
-        .text()?;
+    let client = client(conf)?;
+    let res = check_status(send(
+        client
+            .patch(&format!(
+                "{}/{}",
+                ISSUES_BASE_URL
+                    .replace("{base_url}", &conf.base_url)
+                    .replace("{repo}", &conf.repo),
+                &token.issue_id.to_string()
+            ))
+            .header("Authorization", format!("token {}", &conf.auth_token))
+            .json(&EditIssueOption {
+                state: Some("closed".to_string()),
+                ..Default::default()
+            }),
+        true,
+        conf,
+    )?)?
+    .text()?;
 
     let map: serde_json::map::Map<String, serde_json::Value> = serde_json::from_str(&res)?;
     if map["state"] == "closed" {
-        let (issue_id, title, is_subscribed) = results.remove(0);
-        Ok((title, issue_id, is_subscribed))
+        db::set_state(conn, token.issue_id, true, map["closed_at"].as_str())?;
+        Ok((token.title, token.issue_id, token.subscribed))
     } else {
         eprintln!("Issue could not be closed: {:#?}", map);
         Err(Error::new(
@@ -212,21 +486,56 @@ pub fn close(
     }
 }
 
+/// Replace the body of issue `id` on Gitea.
+pub fn edit_issue_body(id: i64, body: String, conf: &Configuration) -> Result<()> {
+    let client = client(conf)?;
+    check_status(send(
+        client
+            .patch(format!(
+                "{}/{}",
+                ISSUES_BASE_URL
+                    .replace("{base_url}", &conf.base_url)
+                    .replace("{repo}", &conf.repo),
+                id
+            ))
+            .header("Authorization", format!("token {}", &conf.auth_token))
+            .json(&EditIssueOption {
+                body: Some(body),
+                ..Default::default()
+            }),
+        true,
+        conf,
+    )?)?;
+    Ok(())
+}
+
+/// Replace the body of comment `id` on Gitea.
+pub fn edit_comment(id: i64, body: String, conf: &Configuration) -> Result<()> {
+    let client = client(conf)?;
+    check_status(send(
+        client
+            .patch(format!(
+                "{}/{}",
+                REPO_COMMENTS_URL
+                    .replace("{base_url}", &conf.base_url)
+                    .replace("{repo}", &conf.repo),
+                id
+            ))
+            .header("Authorization", format!("token {}", &conf.auth_token))
+            .json(&CreateIssueCommentOption { body }),
+        true,
+        conf,
+    )?)?;
+    Ok(())
+}
+
 pub fn change_subscription(
     conn: &Connection,
     password: Password,
     new_val: bool,
 ) -> Result<(String, i64, bool)> {
-    let mut stmt = conn.prepare("SELECT id, title, subscribed FROM issue WHERE password = ?")?;
-    let mut results: Vec<(i64, String, bool)> = stmt
-        .query_map([password.as_bytes().to_vec()], |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-        })?
-        .collect::<std::result::Result<Vec<(i64, String, bool)>, _>>()?;
-    if results.is_empty() {
-        return Err(Error::new("Issue not found".to_string()));
-    }
-    let (issue_id, title, is_subscribed) = results.remove(0);
+    let token = db::find_token(conn, password)?;
+    let (issue_id, title, is_subscribed) = (token.issue_id, token.title.clone(), token.subscribed);
     if !is_subscribed && !new_val {
         return Err(Error::new(format!(
             "You are not subscribed to issue `{}`",
@@ -239,8 +548,10 @@ pub fn change_subscription(
         )));
     }
 
-    let mut stmt =
-        conn.prepare("UPDATE issue SET subscribed = (:subscribed) WHERE password = (:password)")?;
+    let mut stmt = conn.prepare(&format!(
+        "UPDATE {} SET subscribed = (:subscribed) WHERE password = (:password)",
+        token.table()
+    ))?;
     assert_eq!(
         stmt.execute(rusqlite::named_params! {
             ":subscribed": &new_val,
@@ -251,22 +562,301 @@ pub fn change_subscription(
     Ok((title, issue_id, is_subscribed))
 }
 
-pub fn comments(
-    id: i64,
-    since: &str,
-    conf: &Configuration,
-) -> Result<Vec<serde_json::map::Map<String, serde_json::Value>>> {
-    let client = reqwest::blocking::Client::new();
-    let result = client
-        .get(
-            &ISSUES_COMMENTS_URL
+/// Ask `address` to confirm they want to follow issue `issue_id`.
+///
+/// Returns the issue title and the new subscription's password, which must only be mailed to
+/// `address` itself: receiving it is what proves the request came from the address' owner.
+pub fn subscribe_request(
+    conn: &Connection,
+    issue_id: i64,
+    address: &Address,
+) -> Result<(String, Password)> {
+    let issue = db::issue(conn, issue_id)?.ok_or_else(|| {
+        Error::new(format!(
+            "Issue #{} was not submitted by e-mail and can not be followed by e-mail.",
+            issue_id
+        ))
+    })?;
+    let address = address.to_string();
+    if address == issue.submitter.to_string() {
+        return Err(Error::new(format!(
+            "You submitted issue `{}`; use the password you received then to subscribe.",
+            &issue.title
+        )));
+    }
+    let mut stmt = conn.prepare(
+        "SELECT confirmed, subscribed FROM subscription WHERE issue_id = ? AND address = ?",
+    )?;
+    let existing = stmt
+        .query_map(rusqlite::params![issue_id, &address], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<std::result::Result<Vec<(bool, bool)>, _>>()?;
+    if existing == [(true, true)] {
+        return Err(Error::new(format!(
+            "You are already subscribed to issue `{}`",
+            &issue.title
+        )));
+    }
+    // A fresh password each time, so a confirmation request that was never answered can't be
+    // used later.
+    let password = Uuid::new_v4();
+    conn.execute(
+        "INSERT INTO subscription (issue_id, address, password, confirmed, subscribed, time_created)
+            VALUES (?1, ?2, ?3, 0, 1, ?4)
+            ON CONFLICT(issue_id, address) DO UPDATE SET
+                password = excluded.password, confirmed = 0, subscribed = 1",
+        rusqlite::params![
+            issue_id,
+            &address,
+            password.as_bytes().to_vec(),
+            chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        ],
+    )?;
+    Ok((issue.title, password))
+}
+
+pub fn confirm_subscription(conn: &Connection, password: Password) -> Result<(String, i64)> {
+    let token = match db::token(conn, password)? {
+        Some(token) if token.subscription.is_some() => token,
+        _ => return Err(Error::new("Subscription request not found")),
+    };
+    if token.confirmed {
+        return Err(Error::new(format!(
+            "You are already subscribed to issue `{}`",
+            &token.title
+        )));
+    }
+    conn.execute(
+        "UPDATE subscription SET confirmed = 1 WHERE password = ?",
+        [password.as_bytes().to_vec()],
+    )?;
+    Ok((token.title, token.issue_id))
+}
+
+pub fn issue(id: i64, conf: &Configuration) -> Result<JsonMap> {
+    get_object(
+        &format!(
+            "{}/{}",
+            ISSUES_BASE_URL
                 .replace("{base_url}", &conf.base_url)
-                .replace("{repo}", &conf.repo)
-                .replace("{index}", &id.to_string()),
-        )
-        .query(&[("since", since)])
-        .send()?
-        .text()?;
-    let result: Vec<_> = serde_json::from_str(&result)?;
-    Ok(result)
+                .replace("{repo}", &conf.repo),
+            id
+        ),
+        conf,
+    )
+}
+
+pub fn comments(id: i64, since: &str, conf: &Configuration) -> Result<Vec<JsonMap>> {
+    get_all_pages(
+        &ISSUES_COMMENTS_URL
+            .replace("{base_url}", &conf.base_url)
+            .replace("{repo}", &conf.repo)
+            .replace("{index}", &id.to_string()),
+        &[("since", since)],
+        conf,
+    )
+}
+
+/// Comments in every issue of `conf.repo` since `since`, to poll all issues at once.
+pub fn repo_comments(since: Option<&str>, conf: &Configuration) -> Result<Vec<JsonMap>> {
+    get_all_pages(
+        &REPO_COMMENTS_URL
+            .replace("{base_url}", &conf.base_url)
+            .replace("{repo}", &conf.repo),
+        &since.map(|s| vec![("since", s)]).unwrap_or_default(),
+        conf,
+    )
+}
+
+/// Issues of `conf.repo`, open or closed, that were updated since `since`. Any comment, state,
+/// label, assignee or milestone change updates an issue.
+pub fn updated_issues(since: Option<&str>, conf: &Configuration) -> Result<Vec<JsonMap>> {
+    let mut query = vec![("state", "all"), ("type", "issues")];
+    query.extend(since.map(|s| ("since", s)));
+    get_all_pages(
+        &ISSUES_BASE_URL
+            .replace("{base_url}", &conf.base_url)
+            .replace("{repo}", &conf.repo),
+        &query,
+        conf,
+    )
+}
+
+/// The number of the issue a comment from [`repo_comments`] belongs to.
+pub fn comment_issue(comment: &JsonMap) -> Result<i64> {
+    let issue_url = gitea_api_mismatch!(comment["issue_url"].as_str());
+    issue_url
+        .rsplit('/')
+        .next()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| {
+            Error::new(format!(
+                "Could not find the issue number in `issue_url` {:?}.",
+                issue_url
+            ))
+        })
+}
+
+/// Everything that happened in an issue since `since`: comments, state, label, assignee and
+/// milestone changes, references etc.
+pub fn timeline(id: i64, since: &str, conf: &Configuration) -> Result<Vec<JsonMap>> {
+    get_all_pages(
+        &ISSUES_TIMELINE_URL
+            .replace("{base_url}", &conf.base_url)
+            .replace("{repo}", &conf.repo)
+            .replace("{index}", &id.to_string()),
+        &[("since", since)],
+        conf,
+    )
+}
+
+pub fn change_muted(
+    conn: &Connection,
+    password: Password,
+    kind: EventKind,
+    mute: bool,
+) -> Result<(String, i64)> {
+    let token = db::find_token(conn, password)?;
+    let mut muted = token.muted.clone();
+    if mute == muted.contains(&kind) {
+        return Err(Error::new(format!(
+            "Notices about {} changes in issue `{}` are already {}.",
+            kind.as_str(),
+            &token.title,
+            if mute { "muted" } else { "enabled" }
+        )));
+    }
+    if mute {
+        muted.push(kind);
+    } else {
+        muted.retain(|k| *k != kind);
+    }
+    let mut stmt = conn.prepare(&format!(
+        "UPDATE {} SET muted = (:muted) WHERE password = (:password)",
+        token.table()
+    ))?;
+    assert_eq!(
+        stmt.execute(rusqlite::named_params! {
+            ":muted": &EventKind::join_list(&muted),
+            ":password": &password.as_bytes().to_vec()
+        })?,
+        1
+    );
+    Ok((token.title, token.issue_id))
+}
+
+/// The user `conf.auth_token` belongs to.
+pub fn current_user(conf: &Configuration) -> Result<JsonMap> {
+    get_object(&USER_URL.replace("{base_url}", &conf.base_url), conf)
+}
+
+pub fn repository(conf: &Configuration) -> Result<JsonMap> {
+    get_object(
+        &REPO_URL
+            .replace("{base_url}", &conf.base_url)
+            .replace("{repo}", &conf.repo),
+        conf,
+    )
+}
+
+pub fn version(conf: &Configuration) -> Result<String> {
+    let map = get_object(&VERSION_URL.replace("{base_url}", &conf.base_url), conf)?;
+    Ok(gitea_api_mismatch!(map["version"].as_str()).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn next_page_link() {
+        let link = headers(&[(
+            "Link",
+            r#"<https://git.meli.delivery/api/v1/repos/meli/meli/issues/comments?limit=30&page=2>; rel="next", <https://git.meli.delivery/api/v1/repos/meli/meli/issues/comments?limit=30&page=4>; rel="last""#,
+        )]);
+        assert_eq!(
+            next_page(&link, 30, 30, 30),
+            NextPage::Link(
+                "https://git.meli.delivery/api/v1/repos/meli/meli/issues/comments?limit=30&page=2"
+                    .to_string()
+            )
+        );
+        // The link is followed even after a short page, but not after an empty one.
+        assert!(matches!(next_page(&link, 3, 33, 30), NextPage::Link(_)));
+        assert_eq!(next_page(&link, 0, 30, 30), NextPage::Done);
+        let last = headers(&[(
+            "Link",
+            r#"<https://git.meli.delivery/api/v1/repos/meli/meli/issues/comments?limit=30&page=1>; rel="first", <https://git.meli.delivery/api/v1/repos/meli/meli/issues/comments?limit=30&page=3>; rel="prev""#,
+        )]);
+        assert_eq!(next_page(&last, 30, 120, 30), NextPage::Done);
+    }
+
+    #[test]
+    fn next_page_total_count() {
+        let total = headers(&[("X-Total-Count", "65")]);
+        assert_eq!(next_page(&total, 30, 30, 30), NextPage::Following);
+        assert_eq!(next_page(&total, 30, 60, 30), NextPage::Following);
+        assert_eq!(next_page(&total, 5, 65, 30), NextPage::Done);
+        // Items may be added while paging; the count is what the server says now.
+        assert_eq!(next_page(&total, 30, 90, 30), NextPage::Done);
+        assert_eq!(next_page(&total, 0, 60, 30), NextPage::Done);
+    }
+
+    #[test]
+    fn next_page_without_headers() {
+        let none = HeaderMap::new();
+        // A full page, as large as the first one, may be followed by more.
+        assert_eq!(next_page(&none, 30, 60, 30), NextPage::Following);
+        assert_eq!(next_page(&none, 29, 59, 30), NextPage::Done);
+        assert_eq!(next_page(&none, 0, 60, 30), NextPage::Done);
+        // A garbled count is ignored.
+        let garbled = headers(&[("X-Total-Count", "many")]);
+        assert_eq!(next_page(&garbled, 30, 30, 30), NextPage::Following);
+        assert_eq!(next_page(&garbled, 10, 40, 30), NextPage::Done);
+    }
+
+    #[test]
+    fn retry_after_header() {
+        let now = chrono::DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let after = |value: &str| retry_after(&headers(&[("Retry-After", value)]), now);
+        assert_eq!(after("30"), Some(Duration::from_secs(30)));
+        assert_eq!(after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(
+            after("Mon, 01 Jan 2024 12:00:20 GMT"),
+            Some(Duration::from_secs(20))
+        );
+        // A date in the past means now.
+        assert_eq!(after("Mon, 01 Jan 2024 11:00:00 GMT"), Some(Duration::ZERO));
+        // Capped, as seconds and as a date.
+        assert_eq!(after("3600"), Some(MAX_RETRY_WAIT));
+        assert_eq!(after("Tue, 02 Jan 2024 12:00:00 GMT"), Some(MAX_RETRY_WAIT));
+        assert_eq!(after("soon"), None);
+        assert_eq!(after("-5"), None);
+        assert_eq!(retry_after(&HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn retry_delay_backoff() {
+        assert_eq!(retry_delay(None, 0, 500), Duration::from_millis(500));
+        assert_eq!(retry_delay(None, 1, 500), Duration::from_secs(1));
+        assert_eq!(retry_delay(None, 3, 500), Duration::from_secs(4));
+        // Many retries don't overflow.
+        assert!(retry_delay(None, 100, 500) > Duration::from_secs(3600));
+        // The server's wait replaces the backoff.
+        assert_eq!(
+            retry_delay(Some(Duration::from_secs(7)), 3, 500),
+            Duration::from_secs(7)
+        );
+    }
 }
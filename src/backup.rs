@@ -0,0 +1,332 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `issue-bot export` and `issue-bot import-db`: the whole database as JSON, to move the bot to
+//! another host or keep a backup that can be read and diffed.
+//!
+//! The export is an object with the `format` of the export itself, the database's
+//! `schema_version` and every table of [`db::TABLES`] as an array of rows, each row an object of
+//! its columns. Passwords, which are stored as the 16 bytes of a UUID, are written as the UUID,
+//! eg. `"67e55044-10b1-426f-9247-bb680e5fe0c8"`.
+
+use super::*;
+use rusqlite::types::{Value, ValueRef};
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+
+/// Version of the layout of exports, changed if it does.
+const FORMAT: u64 = 1;
+
+/// Columns that hold passwords.
+const PASSWORD_COLUMNS: &[&str] = &["password"];
+
+fn to_json(column: &str, value: ValueRef) -> Result<JsonValue> {
+    Ok(match value {
+        ValueRef::Null => JsonValue::Null,
+        ValueRef::Integer(i) => json!(i),
+        ValueRef::Real(f) => json!(f),
+        ValueRef::Text(t) => json!(std::str::from_utf8(t)?),
+        ValueRef::Blob(b) if PASSWORD_COLUMNS.contains(&column) => {
+            json!(Uuid::from_slice(b)
+                .map_err(|err| Error::new(format!("Invalid password: {}", err)))?
+                .to_string())
+        }
+        ValueRef::Blob(_) => {
+            return Err(Error::new(format!(
+                "Unexpected binary value in column `{}`.",
+                column
+            )))
+        }
+    })
+}
+
+fn from_json(column: &str, value: &JsonValue) -> Result<Value> {
+    Ok(match value {
+        JsonValue::Null => Value::Null,
+        JsonValue::String(s) if PASSWORD_COLUMNS.contains(&column) => Value::Blob(
+            Uuid::parse_str(s)
+                .map_err(|err| Error::new(format!("Invalid password `{}`: {}", s, err)))?
+                .as_bytes()
+                .to_vec(),
+        ),
+        JsonValue::String(s) => Value::Text(s.clone()),
+        JsonValue::Bool(b) => Value::Integer(*b as i64),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        JsonValue::Array(_) | JsonValue::Object(_) => {
+            return Err(Error::new(format!(
+                "Unexpected array or object in column `{}`.",
+                column
+            )))
+        }
+    })
+}
+
+pub fn export(conn: &Connection) -> Result<JsonValue> {
+    // A read transaction, so that the tables are exported as of one moment.
+    let tx = conn.unchecked_transaction()?;
+    let mut tables = JsonMap::new();
+    for (table, columns) in db::TABLES {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT {} FROM {} ORDER BY rowid",
+            columns.join(", "),
+            table
+        ))?;
+        let mut rows = stmt.query([])?;
+        let mut exported = vec![];
+        while let Some(row) = rows.next()? {
+            let mut object = JsonMap::new();
+            for (i, column) in columns.iter().enumerate() {
+                object.insert(column.to_string(), to_json(column, row.get_ref(i)?)?);
+            }
+            exported.push(JsonValue::Object(object));
+        }
+        tables.insert(table.to_string(), JsonValue::Array(exported));
+    }
+    Ok(json!({
+        "format": FORMAT,
+        "schema_version": db::version(&tx)?,
+        "exported_at": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        "tables": tables,
+    }))
+}
+
+/// Restore an [`export`] into `conn`, which must have no data yet. Nothing is written unless the
+/// whole export is valid.
+pub fn restore(conn: &Connection, export: &JsonValue) -> Result<usize> {
+    if export["format"].as_u64() != Some(FORMAT) {
+        return Err(Error::new(format!(
+            "Unsupported export format {}, expected {}.",
+            export["format"], FORMAT
+        )));
+    }
+    let version = db::version(conn)?;
+    if export["schema_version"].as_u64() != Some(version as u64) {
+        return Err(Error::new(format!(
+            "The export is of schema version {}, this database is version {}. Restore it with the \
+             version of issue-bot that exported it.",
+            export["schema_version"], version
+        )));
+    }
+    let tables = export["tables"]
+        .as_object()
+        .ok_or_else(|| Error::new("The export has no `tables`."))?;
+    if let Some(unknown) = tables
+        .keys()
+        .find(|t| !db::TABLES.iter().any(|(table, _)| table == t))
+    {
+        return Err(Error::new(format!("Unknown table `{}`.", unknown)));
+    }
+    let tx = db::write_transaction(conn)?;
+    let mut count = 0;
+    for (table, columns) in db::TABLES {
+        let existing: i64 =
+            tx.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })?;
+        if existing > 0 {
+            return Err(Error::new(format!(
+                "Table `{}` is not empty: restore into a new database.",
+                table
+            )));
+        }
+        let rows = match tables.get(*table) {
+            Some(JsonValue::Array(rows)) => rows.as_slice(),
+            Some(_) => return Err(Error::new(format!("Table `{}` is not an array.", table))),
+            None => &[],
+        };
+        let mut stmt = tx.prepare(&format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            table,
+            columns.join(", "),
+            vec!["?"; columns.len()].join(", ")
+        ))?;
+        for (i, row) in rows.iter().enumerate() {
+            let context = |err: Error| Error::new(format!("`{}` row {}: {}", table, i + 1, err));
+            let row = row
+                .as_object()
+                .ok_or_else(|| context(Error::new("not an object")))?;
+            if let Some(unknown) = row.keys().find(|c| !columns.contains(&c.as_str())) {
+                return Err(context(Error::new(format!("unknown column `{}`", unknown))));
+            }
+            let values = columns
+                .iter()
+                .map(|c| from_json(c, row.get(*c).unwrap_or(&JsonValue::Null)))
+                .collect::<Result<Vec<Value>>>()
+                .map_err(context)?;
+            stmt.execute(rusqlite::params_from_iter(values))
+                .map_err(|err| context(err.into()))?;
+            count += 1;
+        }
+    }
+    let dangling: i64 =
+        tx.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| {
+            row.get(0)
+        })?;
+    if dangling > 0 {
+        return Err(Error::new(format!(
+            "{} row(s) refer to issues that are not in the export.",
+            dangling
+        )));
+    }
+    tx.commit()?;
+    Ok(count)
+}
+
+/// `issue-bot export [<file>]`
+pub fn run_export(conn: &Connection, args: &[&str]) -> Result<()> {
+    let export = serde_json::to_string_pretty(&export(conn)?)?;
+    match args {
+        [] => println!("{}", export),
+        [path] => std::fs::write(path, export + "\n")?,
+        _ => return Err(Error::new("Usage: issue_bot export [<file>]")),
+    }
+    Ok(())
+}
+
+/// `issue-bot import-db <file>`
+pub fn run_restore(conn: &Connection, conf: &Configuration, args: &[&str]) -> Result<()> {
+    let [path] = args else {
+        return Err(Error::new("Usage: issue_bot import-db <file>"));
+    };
+    let export: JsonValue = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if conf.dry_run {
+        println!("DRY_RUN: NOT restoring {}.", path);
+        return Ok(());
+    }
+    let count = restore(conn, &export)?;
+    println!("{} row(s) restored.", count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every row of every table, as stored.
+    fn dump(conn: &Connection) -> Vec<(&'static str, Vec<Vec<Value>>)> {
+        db::TABLES
+            .iter()
+            .map(|(table, columns)| {
+                let mut stmt = conn
+                    .prepare(&format!(
+                        "SELECT {} FROM {} ORDER BY rowid",
+                        columns.join(", "),
+                        table
+                    ))
+                    .unwrap();
+                let rows = stmt
+                    .query_map([], |row| {
+                        (0..columns.len())
+                            .map(|i| row.get::<_, Value>(i))
+                            .collect::<rusqlite::Result<Vec<Value>>>()
+                    })
+                    .unwrap()
+                    .collect::<rusqlite::Result<Vec<Vec<Value>>>>()
+                    .unwrap();
+                (*table, rows)
+            })
+            .collect()
+    }
+
+    fn filled() -> Connection {
+        let conn = db::open_test();
+        db::insert_test_issue(&conn, 1, "jane@example.com", "2024-01-01T00:00:00Z");
+        let issue = db::insert_test_issue(&conn, 2, "john@example.com", "2024-01-02T00:00:00Z");
+        conn.execute_batch(
+            "UPDATE issue SET anonymous = 1, muted = 'labels state', state = 'closed',
+                closed_at = '2024-01-03T00:00:00Z' WHERE id = 2;
+            INSERT INTO subscriber (address, delivery, last_digest, language, html)
+                VALUES ('jane@example.com', 'weekly', '2024-01-01T00:00:00Z', 'de', 0);
+            INSERT INTO queue (recipient, subject, message, time_created, attempts,
+                next_attempt, last_error, status)
+                VALUES ('john@example.com', 'Re: Issue 2', 'Hello,\n\nbye.', '2024-01-01T00:00:00Z',
+                    3, '2024-01-01T01:00:00Z', 'exit status 1', 'failed');
+            INSERT INTO meta (key, value) VALUES ('cron_since', '2024-01-01T00:00:00Z');",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO subscription (issue_id, address, password, confirmed, muted, time_created)
+                VALUES (2, 'jane@example.com', ?, 1, 'milestone', '2024-01-02T00:00:00Z')",
+            [Uuid::new_v4().as_bytes().to_vec()],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO pending_update (address, issue_id, body, time_created, password, html)
+                VALUES ('jane@example.com', 2, 'Labels: `bug`', '2024-01-02T00:00:00Z', ?, NULL)",
+            [issue.password.as_bytes().to_vec()],
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn round_trip() {
+        let conn = filled();
+        let export = export(&conn).unwrap();
+        assert_eq!(
+            export["tables"]["subscription"][0]["password"]
+                .as_str()
+                .map(str::len),
+            Some(36)
+        );
+        let target = db::open_test();
+        assert_eq!(restore(&target, &export).unwrap(), 7);
+        assert_eq!(dump(&target), dump(&conn));
+    }
+
+    /// Restore `export` into a new database, holding one issue if `occupied`, expecting `error`
+    /// and the database to be left as it was.
+    fn rejected(export: &JsonValue, occupied: bool, error: &str) {
+        let target = db::open_test();
+        if occupied {
+            db::insert_test_issue(&target, 3, "jane@example.com", "2024-01-01T00:00:00Z");
+        }
+        let before = dump(&target);
+        let err = restore(&target, export).unwrap_err().to_string();
+        assert!(err.contains(error), "{}", err);
+        assert_eq!(dump(&target), before);
+    }
+
+    #[test]
+    fn rejections() {
+        let export = export(&filled()).unwrap();
+        rejected(&export, true, "Table `issue` is not empty");
+
+        let mut format = export.clone();
+        format["format"] = json!(FORMAT + 1);
+        rejected(&format, false, "Unsupported export format");
+
+        let mut version = export.clone();
+        version["schema_version"] = json!(1);
+        rejected(&version, false, "schema version 1");
+
+        // The following fail after the issues have been inserted, which must be rolled back.
+        let mut uuid = export.clone();
+        uuid["tables"]["subscription"][0]["password"] = json!("not-a-uuid");
+        rejected(&uuid, false, "Invalid password `not-a-uuid`");
+
+        let mut unknown = export.clone();
+        unknown["tables"]["subscriber"][0]["colour"] = json!("blue");
+        rejected(&unknown, false, "unknown column `colour`");
+
+        // The subscription and pending update refer to issue 2, which is left out.
+        let mut dangling = export.clone();
+        dangling["tables"]["issue"] = json!([export["tables"]["issue"][0]]);
+        rejected(&dangling, false, "`subscription` row 1");
+    }
+}
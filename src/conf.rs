@@ -14,6 +14,8 @@
  * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use super::transport::Transport;
+use super::{Error, Result};
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
@@ -36,10 +38,271 @@ pub struct Configuration {
     /// The bot's login username
     pub bot_username: String,
     /// the command to pipe an email to
-    pub mailer: String,
+    #[serde(default)]
+    pub mailer: Option<String>,
+    /// submit mail to an SMTP server instead of piping it to `mailer`
+    #[serde(default)]
+    pub smtp: Option<melib::smtp::SmtpServerConf>,
     /// file to write logs
     pub log_file: String,
+    /// directory with templates that replace the built-in ones, eg. `new_issue_success.txt`, and
+    /// subdirectories with translations, eg. `de/new_issue_success.txt`
+    #[serde(default)]
+    pub template_dir: Option<String>,
+    /// the language of the templates at the top of `template_dir`, and of replies to people who
+    /// haven't asked for one
+    #[serde(default = "default_language")]
+    pub language: String,
+    /// length that lines of outgoing plain text are wrapped at, see `mail::flowed`
+    #[serde(default = "default_wrap_width")]
+    pub wrap_width: usize,
     /// don't actually email anything
     #[serde(default)]
     pub dry_run: bool,
+    /// settings for talking to Gitea's API
+    #[serde(default)]
+    pub http: HttpConfiguration,
+    /// settings for `issue-bot cron`
+    #[serde(default)]
+    pub cron: CronConfiguration,
+    /// settings for `issue-bot daemon`
+    #[serde(default)]
+    pub daemon: DaemonConfiguration,
+    /// retry policy for the outgoing mail queue
+    #[serde(default)]
+    pub queue: QueueConfiguration,
+    /// settings for `issue-bot serve`
+    #[serde(default)]
+    pub webhook: Option<WebhookConfiguration>,
+}
+
+impl Configuration {
+    /// Check the values that deserialising alone doesn't.
+    pub fn validate(&self) -> Result<()> {
+        self.http.request_interval()?;
+        self.transport()?;
+        Ok(())
+    }
+
+    pub fn transport(&self) -> Result<Transport<'_>> {
+        match (&self.mailer, &self.smtp) {
+            (Some(mailer), None) => Ok(Transport::Sendmail(mailer)),
+            (None, Some(smtp)) => Ok(Transport::Smtp(smtp)),
+            (None, None) => Err(Error::new(
+                "No mail transport configured: set either `mailer` or `[smtp]`.",
+            )),
+            (Some(_), Some(_)) => Err(Error::new(
+                "Both `mailer` and `[smtp]` are set; only one mail transport can be used.",
+            )),
+        }
+    }
+}
+
+fn default_language() -> String {
+    "en".to_string()
+}
+
+fn default_wrap_width() -> usize {
+    72
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct HttpConfiguration {
+    /// seconds to wait for a connection to Gitea
+    pub connect_timeout: u64,
+    /// seconds to wait for a whole request, including the response body
+    pub timeout: u64,
+    /// how many times to retry a failed request
+    pub retries: u32,
+    /// milliseconds to wait before the first retry, doubled after every attempt
+    pub backoff: u64,
+    /// eg. "http://proxy.example.tld:3128"
+    pub proxy: Option<String>,
+    /// path to a PEM file with extra CA certificates, eg. for a self-signed Gitea
+    pub ca_bundle: Option<String>,
+    /// cap on requests per second to Gitea across all threads, 0 for no limit
+    pub max_requests_per_second: f64,
+}
+
+impl HttpConfiguration {
+    /// The least time between two requests under `max_requests_per_second`, or `None` for no
+    /// limit.
+    pub fn request_interval(&self) -> Result<Option<std::time::Duration>> {
+        let rate = self.max_requests_per_second;
+        if rate == 0.0 {
+            return Ok(None);
+        }
+        if rate.is_nan() || rate < 0.0 {
+            return Err(Error::new(format!(
+                "http.max_requests_per_second must be a positive number or 0, not {}.",
+                rate
+            )));
+        }
+        std::time::Duration::try_from_secs_f64(1.0 / rate)
+            .map(Some)
+            .map_err(|_| {
+                Error::new(format!(
+                    "http.max_requests_per_second {} is too small.",
+                    rate
+                ))
+            })
+    }
+}
+
+impl Default for HttpConfiguration {
+    fn default() -> Self {
+        HttpConfiguration {
+            connect_timeout: 10,
+            timeout: 30,
+            retries: 3,
+            backoff: 500,
+            proxy: None,
+            ca_bundle: None,
+            max_requests_per_second: 0.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct CronConfiguration {
+    /// how many issues to check concurrently
+    pub workers: usize,
+    /// days after which closed issues are no longer checked
+    pub closed_grace_days: u64,
+}
+
+impl Default for CronConfiguration {
+    fn default() -> Self {
+        CronConfiguration {
+            workers: 4,
+            closed_grace_days: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct DaemonConfiguration {
+    /// seconds between the start of two checks
+    pub interval: u64,
+}
+
+impl Default for DaemonConfiguration {
+    fn default() -> Self {
+        DaemonConfiguration { interval: 300 }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct QueueConfiguration {
+    /// give up on a message after this many failed deliveries
+    pub max_attempts: u32,
+    /// seconds to wait before retrying a failed delivery, doubled after every attempt
+    pub retry_backoff: u64,
+}
+
+impl Default for QueueConfiguration {
+    fn default() -> Self {
+        QueueConfiguration {
+            max_attempts: 10,
+            retry_backoff: 300,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfiguration {
+    /// address to listen on, eg. "127.0.0.1:8008"
+    pub listen: String,
+    /// the secret configured in Gitea's webhook settings
+    pub secret: String,
+    /// URL the listener is reachable at from outside, eg. "https://issues.example.com", to offer
+    /// one-click unsubscription in notices
+    #[serde(default)]
+    pub public_url: Option<String>,
+}
+
+/// The required settings, for tests.
+#[cfg(test)]
+const EXAMPLE: &str = r#"
+    tag = "meli-issues"
+    auth_token = ""
+    local_part = "issues"
+    domain = "meli.delivery"
+    base_url = "https://git.meli.delivery"
+    repo = "meli/meli"
+    bot_name = "issue-bot"
+    bot_username = "issue-bot"
+    log_file = "/dev/null"
+    "#;
+
+/// A configuration with only the required settings, for tests.
+#[cfg(test)]
+pub fn example() -> Configuration {
+    toml::from_str(EXAMPLE).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(rate: f64) -> Result<Option<std::time::Duration>> {
+        HttpConfiguration {
+            max_requests_per_second: rate,
+            ..Default::default()
+        }
+        .request_interval()
+    }
+
+    #[test]
+    fn request_interval() {
+        assert_eq!(interval(0.0).unwrap(), None);
+        assert_eq!(
+            interval(4.0).unwrap(),
+            Some(std::time::Duration::from_millis(250))
+        );
+        assert_eq!(
+            interval(f64::INFINITY).unwrap(),
+            Some(std::time::Duration::ZERO)
+        );
+        assert!(interval(-1.0).is_err());
+        assert!(interval(f64::NAN).is_err());
+        assert!(interval(1e-300).is_err());
+    }
+
+    /// [`EXAMPLE`] with `extra` settings, validated.
+    fn validated(extra: &str) -> Result<Configuration> {
+        let conf: Configuration = toml::from_str(&format!("{}{}", EXAMPLE, extra))?;
+        conf.validate()?;
+        Ok(conf)
+    }
+
+    const SMTP: &str = r#"
+        [smtp]
+        hostname = "mail.meli.delivery"
+        port = 587
+        envelope_from = "issues@meli.delivery"
+        auth = { type = "auto", username = "issues", password = { type = "raw", value = "hunter2" } }
+        security = { type = "STARTTLS" }
+        "#;
+
+    #[test]
+    fn one_transport() {
+        assert!(validated(r#"mailer = "/usr/sbin/sendmail -t""#).is_ok());
+        assert!(validated(SMTP).is_ok());
+        let err = validated("").unwrap_err().to_string();
+        assert!(err.contains("No mail transport configured"), "{}", err);
+        let err = validated(&format!(r#"mailer = "/usr/sbin/sendmail -t"{}"#, SMTP))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Both `mailer` and `[smtp]` are set"),
+            "{}",
+            err
+        );
+    }
 }
@@ -16,46 +16,200 @@
 
 use super::*;
 use melib::email::address::Address;
+use rusqlite::OptionalExtension;
+use std::collections::HashMap;
+use std::sync::{mpsc, Mutex};
 
-pub fn check_issue(conn: &Connection, conf: &Configuration, issue: Issue) -> Result<bool> {
-    let mut update = false;
-    let mut comments = api::comments(issue.id, &issue.last_update, conf)?;
-    let mut new_value = issue.last_update.clone();
-    for c in &comments {
+/// What Gitea reported for an issue since its `last_update`, before anything is mailed or
+/// written to the database.
+pub struct Activity {
+    comments: Vec<serde_json::map::Map<String, serde_json::Value>>,
+    timeline: Vec<serde_json::map::Map<String, serde_json::Value>>,
+    /// Whether the issue is closed and since when, fetched only for issues whose state isn't
+    /// known yet, eg. ones tracked before the bot recorded it.
+    state: Option<(bool, Option<String>)>,
+}
+
+/// The network half of a check. It doesn't touch the database, so it can run on any thread.
+pub fn fetch_activity(issue: &Issue, conf: &Configuration) -> Result<Activity> {
+    let state = if issue.closed.is_none() {
+        let map = api::issue(issue.id, conf)?;
+        Some((
+            gitea_api_mismatch!(map["state"].as_str()) == "closed",
+            map["closed_at"].as_str().map(str::to_string),
+        ))
+    } else {
+        None
+    };
+    Ok(Activity {
+        state,
+        comments: api::comments(issue.id, &issue.last_update, conf)?,
+        timeline: api::timeline(issue.id, &issue.last_update, conf)?,
+    })
+}
+
+/// Like [`fetch_activity`], for when the issue's comments and its entry in the list of recently
+/// updated issues have already been fetched for the whole repository. The timeline is only
+/// fetched if the issue was updated after `last_update`.
+fn prefetched_activity(
+    issue: &Issue,
+    comments: Vec<serde_json::map::Map<String, serde_json::Value>>,
+    updated: Option<serde_json::map::Map<String, serde_json::Value>>,
+    conf: &Configuration,
+) -> Result<Activity> {
+    let Some(updated) = updated else {
+        return Ok(Activity {
+            state: if issue.closed.is_none() {
+                fetch_activity(issue, conf)?.state
+            } else {
+                None
+            },
+            comments,
+            timeline: vec![],
+        });
+    };
+    let updated_at = gitea_api_mismatch!(updated["updated_at"].as_str());
+    Ok(Activity {
+        timeline: if cmp_timestamps(updated_at, &issue.last_update).is_gt() {
+            api::timeline(issue.id, &issue.last_update, conf)?
+        } else {
+            vec![]
+        },
+        state: Some((
+            gitea_api_mismatch!(updated["state"].as_str()) == "closed",
+            updated["closed_at"].as_str().map(str::to_string),
+        )),
+        comments,
+    })
+}
+
+/// Compare two Gitea timestamps, which may not have the same UTC offset.
+fn cmp_timestamps(a: &str, b: &str) -> std::cmp::Ordering {
+    match (
+        chrono::DateTime::parse_from_rfc3339(a),
+        chrono::DateTime::parse_from_rfc3339(b),
+    ) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+/// Notify subscribers about `activity` and move the issue's `last_update` past it.
+pub fn process_activity(
+    conn: &Connection,
+    conf: &Configuration,
+    issue: Issue,
+    activity: Activity,
+) -> Result<bool> {
+    let Activity {
+        mut comments,
+        mut timeline,
+        mut state,
+    } = activity;
+    for c in comments.iter().chain(timeline.iter()) {
         _ = gitea_api_mismatch!(c["created_at"].as_str());
     }
-    comments.retain(|c| {
-        // Unwrap is safe since we checked above in the forloop
-        let created_at = c["created_at"].as_str().unwrap().to_string();
-        if created_at > issue.last_update {
-            if created_at > new_value {
-                new_value = created_at;
-                update = true;
+    // Unwrap is safe since we checked above in the forloop
+    let created_at = |c: &serde_json::map::Map<String, serde_json::Value>| {
+        c["created_at"].as_str().unwrap().to_string()
+    };
+    let is_new = |c: &serde_json::map::Map<String, serde_json::Value>| {
+        cmp_timestamps(&created_at(c), &issue.last_update).is_gt()
+    };
+    comments.retain(is_new);
+    timeline.retain(is_new);
+    // The timeline is fetched after the comments, so it can list comments posted in between.
+    // Notify them too: `last_update` moves past them.
+    for entry in &timeline {
+        if entry["type"].as_str() == Some("comment")
+            && !comments.iter().any(|c| c["id"] == entry["id"])
+        {
+            comments.push(entry.clone());
+        }
+    }
+    comments.sort_by(|a, b| cmp_timestamps(&created_at(a), &created_at(b)));
+    let mut events = vec![];
+    let mut notified = comments.iter().map(created_at).collect::<Vec<String>>();
+    for c in &timeline {
+        if let Some(event) = Event::from_timeline(c)? {
+            if let Event::State { closed, .. } = &event {
+                state = Some((*closed, Some(created_at(c))));
             }
-            true
-        } else {
-            false
+            events.push(event);
+            notified.push(created_at(c));
         }
-    });
+    }
+    // Only move past what is notified, so that nothing left out is skipped for good.
+    let new_value = notified.into_iter().max_by(|a, b| cmp_timestamps(a, b));
+    let update = new_value.is_some();
+    let new_value = new_value.unwrap_or_else(|| issue.last_update.clone());
+    // Hold the write lock from checking `last_update` until it is moved forward, so that if a
+    // `serve` process and `cron` both fetched this activity only one of them sends notices.
+    let tx = db::write_transaction(conn)?;
+    let current: Option<String> = tx
+        .query_row(
+            "SELECT last_update FROM issue WHERE id = ?",
+            [issue.id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if current.unwrap_or_default() != issue.last_update {
+        log::info!(
+            "Issue {} was checked by another process in the meantime, skipping.",
+            issue.id
+        );
+        return Ok(false);
+    }
+    let mut queued = vec![];
+    if let Some((closed, closed_at)) = state {
+        if !conf.dry_run {
+            db::set_state(&tx, issue.id, closed, closed_at.as_deref())?;
+        }
+    }
     if update {
-        if issue.subscribed {
-            let comments = comments
-                .into_iter()
-                .map(|c| {
-                    let u = &c["user"];
-                    Ok(
-                        if gitea_api_mismatch!(u["login"].as_str()) == conf.bot_username {
-                            gitea_api_mismatch!(c["body"].as_str()).to_string()
-                        } else {
-                            format!(
-                                "User {} replied:\n\n{}",
-                                c["user"]["login"],
-                                gitea_api_mismatch!(c["body"].as_str())
-                            )
-                        },
-                    )
+        let comments = comments
+            .into_iter()
+            .map(|c| {
+                let u = &c["user"];
+                let login = gitea_api_mismatch!(u["login"].as_str());
+                Ok(templates::Comment {
+                    issue_id: issue.id,
+                    author: if login == conf.bot_username {
+                        None
+                    } else {
+                        Some(login.to_string())
+                    },
+                    body: gitea_api_mismatch!(c["body"].as_str()).to_string(),
                 })
-                .collect::<Result<Vec<String>>>()?;
+            })
+            .collect::<Result<Vec<templates::Comment>>>()?;
+        for subscriber in db::subscribers(&tx, &issue)? {
+            let events = events
+                .iter()
+                .filter(|e| !subscriber.muted.contains(&e.kind()))
+                .collect::<Vec<&Event>>();
+            if comments.is_empty() && events.is_empty() {
+                continue;
+            }
+            let lang = db::language(&tx, &subscriber.address.to_string())?;
+            let lang = lang.as_deref();
+            let html = db::html(&tx, &subscriber.address.to_string())?;
+            if digest::delivery(&tx, &subscriber.address.to_string())? != Delivery::Immediate {
+                if !conf.dry_run {
+                    digest::queue_update(
+                        &tx,
+                        &subscriber,
+                        issue.id,
+                        &templates::update_sections(&events, &comments, lang, conf)?,
+                        html.then(|| {
+                            templates::update_sections_html(&events, &comments, lang, conf)
+                        })
+                        .transpose()?
+                        .as_deref(),
+                    )?;
+                }
+                continue;
+            }
             let mut notice = melib::Draft::default();
             notice.headers_mut().insert(
                 HeaderName::new_unchecked("From"),
@@ -71,22 +225,45 @@ pub fn check_issue(conn: &Connection, conf: &Configuration, issue: Issue) -> Res
             );
             notice.headers_mut().insert(
                 HeaderName::new_unchecked("Subject"),
-                format!(
-                    "[{tag}] new replies in issue `{title}`",
-                    tag = &conf.tag,
-                    title = &issue.title
-                ),
+                templates::subject(
+                    "reply_update",
+                    context! { title => &issue.title, replies => !comments.is_empty() },
+                    lang,
+                    conf,
+                )?,
             );
-            notice
-                .headers_mut()
-                .insert(HeaderName::new_unchecked("To"), issue.submitter.to_string());
+            notice.headers_mut().insert(
+                HeaderName::new_unchecked("To"),
+                subscriber.address.to_string(),
+            );
+            mail::add_list_headers(&mut notice, &issue, subscriber.password, conf);
 
-            notice.set_body(templates::reply_update(&issue, conf, comments));
-            send_mail(notice, conf)?;
+            notice.set_body(templates::reply_update(
+                &issue,
+                &subscriber,
+                lang,
+                conf,
+                &events,
+                &comments,
+                html,
+            )?);
+            let html = if html {
+                Some(templates::reply_update_html(
+                    &issue,
+                    &subscriber,
+                    lang,
+                    conf,
+                    &events,
+                    &comments,
+                )?)
+            } else {
+                None
+            };
+            queued.extend(queue_mail(&tx, notice, html.as_deref(), conf)?);
         }
         if !conf.dry_run {
             let mut stmt =
-                conn.prepare("UPDATE issue SET last_update = (:last_update) WHERE id = (:id)")?;
+                tx.prepare("UPDATE issue SET last_update = (:last_update) WHERE id = (:id)")?;
             assert_eq!(
                 stmt.execute(
                     rusqlite::named_params! {":last_update": &new_value, ":id": &issue.id}
@@ -95,32 +272,120 @@ pub fn check_issue(conn: &Connection, conf: &Configuration, issue: Issue) -> Res
             );
         }
     }
+    tx.commit()?;
+    deliver_queued(conn, conf, &queued);
 
     Ok(update)
 }
 
-pub fn check(conn: Connection, conf: Configuration) -> Result<()> {
-    let mut stmt = conn.prepare("SELECT * FROM issue")?;
+pub fn check(conn: &Connection, conf: &Configuration) -> Result<()> {
+    if !conf.dry_run {
+        let (sent, failed) = queue::drain(conn, conf)?;
+        if sent + failed > 0 {
+            log::info!(
+                "Retried queued mail: {} delivered, {} failed.",
+                sent,
+                failed
+            );
+        }
+    }
+    // Closed issues are only checked for a while after they were closed, in case someone has
+    // something to add. `serve` still picks up activity in them and reopening brings them back.
+    let cutoff = (chrono::Utc::now() - chrono::Duration::days(conf.cron.closed_grace_days as i64))
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let mut stmt = conn.prepare(
+        "SELECT * FROM issue WHERE state IS NOT 'closed' OR closed_at IS NULL OR closed_at >= ?",
+    )?;
     let results = stmt
-        .query_map([], |row| {
-            let submitter: String = row.get(1)?;
-            let password: uuid::Uuid = row.get(2)?;
-            let last_update: Option<String> = row.get(7)?;
-            Ok(Issue {
-                id: row.get(0)?,
-                submitter: Address::new(None, submitter.as_str().to_string()),
-                password,
-                time_created: row.get(3)?,
-                anonymous: row.get(4)?,
-                subscribed: row.get(5)?,
-                title: row.get(6)?,
-                last_update: last_update.unwrap_or_default(),
-            })
-        })?
+        .query_map([&cutoff], db::issue_from_row)?
         .collect::<std::result::Result<Vec<Issue>, _>>()?;
     let mut errors: Vec<Result<bool>> = vec![];
-    for issue in results {
-        errors.push(check_issue(&conn, &conf, issue));
+    // Ask for the comments and updated issues of the whole repository, so that only issues that
+    // changed need requests of their own. Activity older than the newest timestamp the last
+    // complete run saw has been handled; before the first one, start from the oldest
+    // `last_update`.
+    let since = match db::meta(conn, db::CRON_SINCE)? {
+        Some(since) => Some(since),
+        None => results
+            .iter()
+            .map(|i| i.last_update.as_str())
+            .filter(|s| !s.is_empty())
+            .min_by(|a, b| cmp_timestamps(a, b))
+            .map(str::to_string),
+    };
+    let mut newest: Option<String> = None;
+    let mut see = |map: &serde_json::map::Map<String, serde_json::Value>| -> Result<()> {
+        let updated_at = gitea_api_mismatch!(map["updated_at"].as_str());
+        if newest
+            .as_deref()
+            .is_none_or(|n| cmp_timestamps(updated_at, n).is_gt())
+        {
+            newest = Some(updated_at.to_string());
+        }
+        Ok(())
+    };
+    let mut comments: HashMap<i64, Vec<_>> = HashMap::new();
+    let mut updated = HashMap::new();
+    if !results.is_empty() {
+        for c in api::repo_comments(since.as_deref(), conf)? {
+            see(&c)?;
+            comments.entry(api::comment_issue(&c)?).or_default().push(c);
+        }
+        for i in api::updated_issues(since.as_deref(), conf)? {
+            see(&i)?;
+            updated.insert(gitea_api_mismatch!(i["number"].as_i64()), i);
+        }
+    }
+    let results = results
+        .into_iter()
+        .map(|issue| {
+            let id = issue.id;
+            (
+                issue,
+                comments.remove(&id).unwrap_or_default(),
+                updated.remove(&id),
+            )
+        })
+        .collect::<Vec<_>>();
+    // Workers only talk to Gitea; everything that touches the database or sends mail happens
+    // on this thread, one issue at a time.
+    let workers = conf.cron.workers.clamp(1, results.len().max(1));
+    let issues = Mutex::new(results.into_iter());
+    let (tx, rx) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let issues = &issues;
+            scope.spawn(move || loop {
+                let Some((issue, comments, updated)) = issues.lock().unwrap().next() else {
+                    break;
+                };
+                let activity = prefetched_activity(&issue, comments, updated, conf);
+                if tx.send((issue, activity)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+        for (issue, activity) in rx {
+            let id = issue.id;
+            let result = activity.and_then(|a| process_activity(conn, conf, issue, a));
+            if let Err(ref err) = result {
+                log::error!("Could not check issue {}: {}", id, err);
+            }
+            errors.push(result);
+        }
+    });
+    // Issues that failed are retried next time, so their activity must still be in what the
+    // repository-wide queries return then.
+    if let Some(newest) = newest {
+        if !conf.dry_run && errors.iter().all(|r| r.is_ok()) {
+            db::set_meta(conn, db::CRON_SINCE, &newest)?;
+        }
+    }
+    match digest::send_due(conn, conf) {
+        Ok(sent) => log::info!("Sent {} digest(s).", sent),
+        Err(err) => errors.push(Err(err)),
     }
     let successes_count = errors.iter().filter(|r| matches!(r, Ok(true))).count();
     let error_count = errors.iter().filter(|r| r.is_err()).count();
@@ -132,3 +397,86 @@ pub fn check(conn: Connection, conf: Configuration) -> Result<()> {
     _ = errors.into_iter().collect::<Result<Vec<bool>>>()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entries(values: serde_json::Value) -> Vec<serde_json::map::Map<String, serde_json::Value>> {
+        serde_json::from_value(values).unwrap()
+    }
+
+    #[test]
+    fn comment_between_fetches() {
+        let conn = db::open_test();
+        let conf = conf::example();
+        templates::load(&conf).unwrap();
+        let issue = db::insert_test_issue(&conn, 1, "jane@example.com", "2024-01-01T00:00:00Z");
+        // Collect the notices in a digest, where they can be read back.
+        conn.execute(
+            "INSERT INTO subscriber (address, delivery) VALUES (?, 'daily')",
+            [issue.submitter.to_string()],
+        )
+        .unwrap();
+        // The repository-wide comments were fetched before the comment was posted, the
+        // timeline after.
+        let activity = Activity {
+            comments: vec![],
+            timeline: entries(json!([
+                {
+                    "id": 10,
+                    "type": "comment",
+                    "created_at": "2024-01-01T01:15:00+01:00",
+                    "user": {"login": "john"},
+                    "body": "Posted in between."
+                },
+                {
+                    "id": 11,
+                    "type": "label",
+                    "created_at": "2024-01-01T00:30:00Z",
+                    "user": {"login": "john"},
+                    "label": {"name": "bug"},
+                    "body": "1"
+                }
+            ])),
+            state: None,
+        };
+        assert!(process_activity(&conn, &conf, issue, activity).unwrap());
+        let body: String = conn
+            .query_row("SELECT body FROM pending_update", [], |row| row.get(0))
+            .unwrap();
+        assert!(body.contains("Posted in between."), "{}", body);
+        assert!(body.contains("`bug`"), "{}", body);
+        let issue = db::issue(&conn, 1).unwrap().unwrap();
+        assert_eq!(issue.last_update, "2024-01-01T00:30:00Z");
+    }
+
+    #[test]
+    fn unnotified_entries_keep_last_update() {
+        let conn = db::open_test();
+        let conf = conf::example();
+        let issue = db::insert_test_issue(&conn, 1, "jane@example.com", "2024-01-01T00:00:00Z");
+        let activity = Activity {
+            comments: vec![],
+            timeline: entries(json!([
+                {
+                    "id": 12,
+                    "type": "commit_ref",
+                    "created_at": "2024-01-02T00:00:00Z",
+                    "user": {"login": "john"}
+                },
+                {
+                    "id": 13,
+                    "type": "close",
+                    "created_at": "2024-01-01T01:00:00+02:00",
+                    "user": {"login": "john"}
+                }
+            ])),
+            state: None,
+        };
+        assert!(!process_activity(&conn, &conf, issue, activity).unwrap());
+        let issue = db::issue(&conn, 1).unwrap().unwrap();
+        assert_eq!(issue.last_update, "2024-01-01T00:00:00Z");
+    }
+}
@@ -0,0 +1,75 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `issue-bot daemon`: run the `cron` check in a loop instead of from a timer.
+//!
+//! The database connection and the HTTP client are kept between runs. `SIGHUP` reloads the
+//! configuration file and `SIGTERM` or `SIGINT` stop the daemon once the current check is done.
+
+use super::*;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often to wake up while waiting for the next check, to notice signals.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn run(conn: Connection, mut conf: Configuration, conf_path: &str) -> Result<()> {
+    let terminate = Arc::new(AtomicBool::new(false));
+    let reload = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&terminate))?;
+    }
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
+    info!(
+        "Starting daemon, checking issues every {} seconds.",
+        conf.daemon.interval
+    );
+    loop {
+        let started = Instant::now();
+        if let Err(err) = cron::check(&conn, &conf) {
+            error!("Check failed: {}", err);
+        }
+        let next = started + Duration::from_secs(conf.daemon.interval);
+        loop {
+            if terminate.load(Ordering::Relaxed) {
+                info!("Shutting down.");
+                return Ok(());
+            }
+            if reload.swap(false, Ordering::Relaxed) {
+                match load_configuration(conf_path)
+                    .and_then(|new_conf| templates::load(&new_conf).map(|()| new_conf))
+                {
+                    Ok(new_conf) => {
+                        conf = new_conf;
+                        api::reset_client();
+                        info!("Reloaded configuration from {}.", conf_path);
+                    }
+                    Err(err) => error!(
+                        "Could not reload configuration, keeping the old one: {}",
+                        err
+                    ),
+                }
+            }
+            let now = Instant::now();
+            if now >= next {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL.min(next - now));
+        }
+    }
+}
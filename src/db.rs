@@ -0,0 +1,808 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::*;
+
+/// Every table and its columns, in declaration order. Issue rows are read by index, so the order
+/// matters.
+pub static TABLES: &[(&str, &[&str])] = &[
+    (
+        "issue",
+        &[
+            "id",
+            "submitter",
+            "password",
+            "time_created",
+            "anonymous",
+            "subscribed",
+            "title",
+            "last_update",
+            "muted",
+            "state",
+            "closed_at",
+        ],
+    ),
+    (
+        "subscription",
+        &[
+            "id",
+            "issue_id",
+            "address",
+            "password",
+            "confirmed",
+            "subscribed",
+            "muted",
+            "time_created",
+        ],
+    ),
+    (
+        "subscriber",
+        &["address", "delivery", "last_digest", "language", "html"],
+    ),
+    (
+        "pending_update",
+        &[
+            "id",
+            "address",
+            "issue_id",
+            "body",
+            "time_created",
+            "password",
+            "html",
+        ],
+    ),
+    (
+        "queue",
+        &[
+            "id",
+            "recipient",
+            "subject",
+            "message",
+            "time_created",
+            "attempts",
+            "next_attempt",
+            "last_error",
+            "status",
+        ],
+    ),
+    ("meta", &["key", "value"]),
+];
+
+/// How long to wait for another process to finish writing before giving up.
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Open the database for use by several processes at once: the MTA may run many instances
+/// while `cron` or `serve` are running.
+pub fn open(path: &str) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // Off by default in SQLite; subscriptions and pending updates are deleted with their issue
+    // through `ON DELETE CASCADE`.
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    let mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+    if !mode.eq_ignore_ascii_case("wal") {
+        log::warn!("Could not enable WAL mode, journal mode is {}.", mode);
+    }
+    Ok(conn)
+}
+
+/// Take an advisory lock on `path`, creating it if needed. Returns `None` if another process
+/// holds it. The lock is released when the returned file is dropped.
+pub fn lock(path: &str) -> Result<Option<File>> {
+    use fs2::FileExt;
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(Some(file)),
+        Err(err) if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Start a transaction that takes the write lock right away, so that what we read in it can't
+/// be changed by another process before we write.
+pub fn write_transaction(conn: &Connection) -> Result<rusqlite::Transaction<'_>> {
+    Ok(rusqlite::Transaction::new_unchecked(
+        conn,
+        rusqlite::TransactionBehavior::Immediate,
+    )?)
+}
+
+/// Schema migrations, in order: migration `i` takes the database from `PRAGMA user_version` `i`
+/// to `i + 1`. Never change or reorder a migration that has been released; add a new one, and
+/// update [`TABLES`] to match.
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "baseline",
+        run: baseline,
+    },
+    Migration {
+        name: "meta",
+        run: meta_table,
+    },
+];
+
+struct Migration {
+    name: &'static str,
+    run: fn(&Connection) -> Result<()>,
+}
+
+/// Bring the database up to date by running the migrations it hasn't had yet, each in its own
+/// transaction.
+pub fn init(conn: &Connection) -> Result<()> {
+    migrate(conn, MIGRATIONS)
+}
+
+fn migrate(conn: &Connection, migrations: &[Migration]) -> Result<()> {
+    loop {
+        // Cheap check first: this runs for every incoming mail.
+        if version(conn)? == migrations.len() {
+            return Ok(());
+        }
+        // Another process may have migrated since, so look again once we hold the write lock.
+        let tx = write_transaction(conn)?;
+        let version = version(&tx)?;
+        if version > migrations.len() {
+            return Err(Error::new(format!(
+                "The database schema is version {}, newer than this version of issue-bot \
+                 supports ({}).",
+                version,
+                migrations.len()
+            )));
+        }
+        if let Some(migration) = migrations.get(version) {
+            (migration.run)(&tx).map_err(|err| {
+                Error::new(format!(
+                    "Migration {} ({}) failed: {}",
+                    version + 1,
+                    migration.name,
+                    err
+                ))
+            })?;
+            tx.execute_batch(&format!("PRAGMA user_version = {};", version + 1))?;
+            log::info!(
+                "Applied database migration {} ({}).",
+                version + 1,
+                migration.name
+            );
+        }
+        tx.commit()?;
+    }
+}
+
+/// The schema version of the database, `PRAGMA user_version`.
+pub fn version(conn: &Connection) -> Result<usize> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+/// The schema as of the first versioned release. Databases from before then are at version 0 in
+/// any of the states older releases left them in, so tables are only created if missing and
+/// columns added since are added where missing.
+fn baseline(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r##"CREATE TABLE IF NOT EXISTS issue (
+                  id              INTEGER PRIMARY KEY,
+                  submitter       TEXT NOT NULL,
+                  password        BLOB,
+                  time_created    TEXT NOT NULL,
+                  anonymous       BOOLEAN,
+                  subscribed      BOOLEAN,
+                  title           TEXT NOT NULL,
+                  last_update     TEXT,
+                  muted           TEXT NOT NULL DEFAULT '',
+                  state           TEXT,
+                  closed_at       TEXT
+                  );
+
+        CREATE TABLE IF NOT EXISTS subscription (
+                  id              INTEGER PRIMARY KEY,
+                  issue_id        INTEGER NOT NULL REFERENCES issue(id) ON DELETE CASCADE,
+                  address         TEXT NOT NULL,
+                  password        BLOB NOT NULL UNIQUE,
+                  confirmed       BOOLEAN NOT NULL DEFAULT 0,
+                  subscribed      BOOLEAN NOT NULL DEFAULT 1,
+                  muted           TEXT NOT NULL DEFAULT '',
+                  time_created    TEXT NOT NULL,
+                  UNIQUE (issue_id, address)
+                  );
+
+        CREATE TABLE IF NOT EXISTS subscriber (
+                  address         TEXT PRIMARY KEY,
+                  delivery        TEXT NOT NULL DEFAULT 'immediate',
+                  last_digest     TEXT,
+                  language        TEXT,
+                  html            BOOLEAN NOT NULL DEFAULT 1
+                  );
+
+        CREATE TABLE IF NOT EXISTS pending_update (
+                  id              INTEGER PRIMARY KEY,
+                  address         TEXT NOT NULL,
+                  issue_id        INTEGER NOT NULL REFERENCES issue(id) ON DELETE CASCADE,
+                  body            TEXT NOT NULL,
+                  time_created    TEXT NOT NULL,
+                  password        BLOB,
+                  html            TEXT
+                  );
+
+        CREATE TABLE IF NOT EXISTS queue (
+                  id              INTEGER PRIMARY KEY,
+                  recipient       TEXT NOT NULL,
+                  subject         TEXT NOT NULL,
+                  message         TEXT NOT NULL,
+                  time_created    TEXT NOT NULL,
+                  attempts        INTEGER NOT NULL DEFAULT 0,
+                  next_attempt    TEXT NOT NULL,
+                  last_error      TEXT,
+                  status          TEXT NOT NULL DEFAULT 'pending'
+                  );
+
+        -- Early versions stored timestamps as JSON strings, quotes included.
+        UPDATE issue SET last_update = replace(last_update, '"', '');
+        "##,
+    )?;
+    ensure_column(conn, "issue", "muted", "TEXT NOT NULL DEFAULT ''")?;
+    ensure_column(conn, "pending_update", "password", "BLOB")?;
+    ensure_column(conn, "issue", "state", "TEXT")?;
+    ensure_column(conn, "issue", "closed_at", "TEXT")?;
+    ensure_column(conn, "subscriber", "language", "TEXT")?;
+    ensure_column(conn, "subscriber", "html", "BOOLEAN NOT NULL DEFAULT 1")?;
+    ensure_column(conn, "pending_update", "html", "TEXT")?;
+    Ok(())
+}
+
+/// Values the bot keeps between runs, see [`meta`].
+fn meta_table(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE meta (
+                  key             TEXT PRIMARY KEY,
+                  value           TEXT NOT NULL
+                  );",
+    )?;
+    Ok(())
+}
+
+/// Add `column` to `table` in databases created by an older version.
+fn ensure_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let count: i64 = conn.query_row(
+        &format!(
+            "SELECT COUNT(*) FROM pragma_table_info('{}') WHERE name = ?",
+            table
+        ),
+        [column],
+        |row| row.get(0),
+    )?;
+    if count == 0 {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {};",
+            table, column, definition
+        ))?;
+    }
+    Ok(())
+}
+
+/// Build an [`Issue`] from a `SELECT * FROM issue` row.
+pub fn issue_from_row(row: &rusqlite::Row) -> rusqlite::Result<Issue> {
+    let submitter: String = row.get(1)?;
+    let password: uuid::Uuid = row.get(2)?;
+    let last_update: Option<String> = row.get(7)?;
+    let muted: String = row.get(8)?;
+    let state: Option<String> = row.get(9)?;
+    Ok(Issue {
+        id: row.get(0)?,
+        submitter: Address::new(None, submitter.as_str().to_string()),
+        password,
+        time_created: row.get(3)?,
+        anonymous: row.get(4)?,
+        subscribed: row.get(5)?,
+        title: row.get(6)?,
+        last_update: last_update.unwrap_or_default(),
+        muted: EventKind::parse_list(&muted),
+        closed: state.map(|s| s == "closed"),
+    })
+}
+
+/// Record whether an issue is closed and since when. `closed_at` is normalised to UTC so that
+/// it can be compared as a string.
+pub fn set_state(
+    conn: &Connection,
+    issue_id: i64,
+    closed: bool,
+    closed_at: Option<&str>,
+) -> Result<()> {
+    let closed_at = closed_at
+        .map(|t| {
+            chrono::DateTime::parse_from_rfc3339(t).map(|t| {
+                t.with_timezone(&chrono::Utc)
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            })
+        })
+        .transpose()
+        .map_err(|err| Error::new(format!("Invalid timestamp {:?}: {}", closed_at, err)))?;
+    conn.execute(
+        "UPDATE issue SET state = ?, closed_at = ? WHERE id = ?",
+        rusqlite::params![
+            if closed { "closed" } else { "open" },
+            if closed { closed_at } else { None },
+            issue_id
+        ],
+    )?;
+    Ok(())
+}
+
+/// Delete issues that were closed before `cutoff` along with their subscriptions and pending
+/// digest updates. Their passwords stop working. Returns the deleted issues' numbers.
+pub fn prune(conn: &Connection, cutoff: chrono::DateTime<chrono::Utc>) -> Result<Vec<i64>> {
+    let cutoff = cutoff.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let tx = conn.unchecked_transaction()?;
+    let ids = tx
+        .prepare("SELECT id FROM issue WHERE state = 'closed' AND closed_at < ? ORDER BY id")?
+        .query_map([&cutoff], |row| row.get(0))?
+        .collect::<std::result::Result<Vec<i64>, _>>()?;
+    for id in &ids {
+        delete_issue(&tx, *id)?;
+    }
+    tx.commit()?;
+    Ok(ids)
+}
+
+/// Delete issue `id` along with its subscriptions and pending digest updates. Returns whether it
+/// was tracked.
+pub fn forget(conn: &Connection, id: i64) -> Result<bool> {
+    let tx = conn.unchecked_transaction()?;
+    let deleted = delete_issue(&tx, id)?;
+    tx.commit()?;
+    Ok(deleted)
+}
+
+/// Subscriptions and pending updates go with the issue, see [`open`].
+fn delete_issue(conn: &Connection, id: i64) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM issue WHERE id = ?", [id])? > 0)
+}
+
+/// A value kept between runs, eg. [`CRON_SINCE`].
+pub fn meta(conn: &Connection, key: &str) -> Result<Option<String>> {
+    use rusqlite::OptionalExtension;
+    Ok(conn
+        .query_row("SELECT value FROM meta WHERE key = ?", [key], |row| {
+            row.get(0)
+        })
+        .optional()?)
+}
+
+pub fn set_meta(conn: &Connection, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2)
+            ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        [key, value],
+    )?;
+    Ok(())
+}
+
+/// The newest Gitea timestamp seen by a `cron` run in which every issue was checked: activity
+/// older than that has been handled.
+pub const CRON_SINCE: &str = "cron_since";
+
+/// Every address stored anywhere in the database, as stored.
+pub fn addresses(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT submitter FROM issue
+         UNION SELECT address FROM subscription
+         UNION SELECT address FROM subscriber
+         UNION SELECT address FROM pending_update
+         UNION SELECT recipient FROM queue",
+    )?;
+    let addresses = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<std::result::Result<Vec<String>, _>>()?;
+    Ok(addresses)
+}
+
+/// Erase `addresses` from the database: their subscriptions, settings, pending updates and queued
+/// mail. The `submitted` issues stay tracked for their other subscribers, with an empty submitter
+/// and a password nobody knows, or are deleted if nobody else follows them.
+pub fn erase(conn: &Connection, addresses: &[String], submitted: &[i64]) -> Result<()> {
+    let tx = write_transaction(conn)?;
+    for address in addresses {
+        tx.execute("DELETE FROM subscription WHERE address = ?", [address])?;
+        tx.execute("DELETE FROM pending_update WHERE address = ?", [address])?;
+        tx.execute("DELETE FROM subscriber WHERE address = ?", [address])?;
+        tx.execute("DELETE FROM queue WHERE recipient = ?", [address])?;
+    }
+    for &id in submitted {
+        let followers: i64 = tx.query_row(
+            "SELECT COUNT(*) FROM subscription WHERE issue_id = ?",
+            [id],
+            |row| row.get(0),
+        )?;
+        if followers == 0 {
+            delete_issue(&tx, id)?;
+        } else {
+            tx.execute(
+                "UPDATE issue SET submitter = '', anonymous = 1, subscribed = 0, password = ?
+                    WHERE id = ?",
+                rusqlite::params![Uuid::new_v4().as_bytes().to_vec(), id],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Every tracked issue.
+pub fn issues(conn: &Connection) -> Result<Vec<Issue>> {
+    let mut stmt = conn.prepare("SELECT * FROM issue ORDER BY id")?;
+    let issues = stmt
+        .query_map([], issue_from_row)?
+        .collect::<std::result::Result<Vec<Issue>, _>>()?;
+    Ok(issues)
+}
+
+/// Look up a tracked issue by its Gitea number.
+pub fn issue(conn: &Connection, id: i64) -> Result<Option<Issue>> {
+    let mut stmt = conn.prepare("SELECT * FROM issue WHERE id = ?")?;
+    let mut results = stmt
+        .query_map([id], issue_from_row)?
+        .collect::<std::result::Result<Vec<Issue>, _>>()?;
+    Ok(results.pop())
+}
+
+/// The language mails to `address` are written in, if it has been set.
+pub fn language(conn: &Connection, address: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare("SELECT language FROM subscriber WHERE address = ?")?;
+    let mut results = stmt
+        .query_map([address], |row| row.get(0))?
+        .collect::<std::result::Result<Vec<Option<String>>, _>>()?;
+    Ok(results.pop().flatten())
+}
+
+pub fn set_language(conn: &Connection, address: &str, language: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO subscriber (address, language) VALUES (?1, ?2)
+            ON CONFLICT(address) DO UPDATE SET language = excluded.language",
+        [address, language],
+    )?;
+    Ok(())
+}
+
+/// Whether `address` receives notices and digests with an HTML part, which is the default.
+pub fn html(conn: &Connection, address: &str) -> Result<bool> {
+    let mut stmt = conn.prepare("SELECT html FROM subscriber WHERE address = ?")?;
+    let mut results = stmt
+        .query_map([address], |row| row.get(0))?
+        .collect::<std::result::Result<Vec<bool>, _>>()?;
+    Ok(results.pop().unwrap_or(true))
+}
+
+/// Change the format of the notices of the owner of `password`, for all their issues.
+pub fn change_format(conn: &Connection, password: Password, html: bool) -> Result<(String, i64)> {
+    let Token {
+        issue_id,
+        title,
+        address,
+        ..
+    } = find_token(conn, password)?;
+    conn.execute(
+        "INSERT INTO subscriber (address, html) VALUES (?1, ?2)
+            ON CONFLICT(address) DO UPDATE SET html = excluded.html",
+        rusqlite::params![&address, html],
+    )?;
+    Ok((title, issue_id))
+}
+
+/// A row of the `subscription` table: someone other than the submitter following an issue.
+#[derive(Debug)]
+pub struct Subscription {
+    pub address: String,
+    pub password: Password,
+    pub confirmed: bool,
+    pub subscribed: bool,
+    pub muted: Vec<EventKind>,
+    pub time_created: String,
+}
+
+/// Every subscription to issue `issue_id`, confirmed or not.
+pub fn subscriptions(conn: &Connection, issue_id: i64) -> Result<Vec<Subscription>> {
+    let mut stmt = conn.prepare(
+        "SELECT address, password, confirmed, subscribed, muted, time_created
+            FROM subscription WHERE issue_id = ? ORDER BY id",
+    )?;
+    let subscriptions = stmt
+        .query_map([issue_id], |row| {
+            let muted: String = row.get(4)?;
+            Ok(Subscription {
+                address: row.get(0)?,
+                password: row.get(1)?,
+                confirmed: row.get(2)?,
+                subscribed: row.get(3)?,
+                muted: EventKind::parse_list(&muted),
+                time_created: row.get(5)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<Subscription>, _>>()?;
+    Ok(subscriptions)
+}
+
+/// Someone who receives update notices for an issue: its submitter or a confirmed subscriber.
+#[derive(Debug)]
+pub struct Subscriber {
+    pub address: Address,
+    pub password: Password,
+    pub muted: Vec<EventKind>,
+}
+
+/// The submitter, if subscribed, followed by every confirmed subscriber of `issue`.
+pub fn subscribers(conn: &Connection, issue: &Issue) -> Result<Vec<Subscriber>> {
+    let mut ret = vec![];
+    if issue.subscribed {
+        ret.push(Subscriber {
+            address: issue.submitter.clone(),
+            password: issue.password,
+            muted: issue.muted.clone(),
+        });
+    }
+    let mut stmt = conn.prepare(
+        "SELECT address, password, muted FROM subscription
+            WHERE issue_id = ? AND confirmed AND subscribed ORDER BY id",
+    )?;
+    for row in stmt.query_map([issue.id], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })? {
+        let (address, password, muted): (String, Password, String) = row?;
+        ret.push(Subscriber {
+            address: Address::new(None, address),
+            password,
+            muted: EventKind::parse_list(&muted),
+        });
+    }
+    Ok(ret)
+}
+
+/// What a password gives access to. Submitters' passwords are kept in `issue`, every other
+/// subscriber's in `subscription`.
+#[derive(Debug)]
+pub struct Token {
+    pub issue_id: i64,
+    pub title: String,
+    pub address: String,
+    pub subscribed: bool,
+    pub anonymous: bool,
+    pub muted: Vec<EventKind>,
+    /// The `subscription` row, or `None` for the submitter.
+    pub subscription: Option<i64>,
+    pub confirmed: bool,
+}
+
+impl Token {
+    /// The table to update when changing this token's settings.
+    pub fn table(&self) -> &'static str {
+        if self.subscription.is_some() {
+            "subscription"
+        } else {
+            "issue"
+        }
+    }
+}
+
+/// Look up `password`, including subscriptions that have not been confirmed yet.
+pub fn token(conn: &Connection, password: Password) -> Result<Option<Token>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, title, submitter, subscribed, anonymous, muted, NULL, 1
+            FROM issue WHERE password = ?1
+         UNION ALL
+         SELECT issue.id, issue.title, subscription.address, subscription.subscribed, 0,
+            subscription.muted, subscription.id, subscription.confirmed
+            FROM subscription JOIN issue ON issue.id = subscription.issue_id
+            WHERE subscription.password = ?1",
+    )?;
+    let mut results = stmt
+        .query_map([password.as_bytes().to_vec()], |row| {
+            let muted: String = row.get(5)?;
+            Ok(Token {
+                issue_id: row.get(0)?,
+                title: row.get(1)?,
+                address: row.get(2)?,
+                subscribed: row.get(3)?,
+                anonymous: row.get(4)?,
+                muted: EventKind::parse_list(&muted),
+                subscription: row.get(6)?,
+                confirmed: row.get(7)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<Token>, _>>()?;
+    Ok(results.pop())
+}
+
+/// Look up `password` for a command; unconfirmed subscriptions can only be confirmed.
+pub fn find_token(conn: &Connection, password: Password) -> Result<Token> {
+    match token(conn, password)? {
+        Some(token) if token.confirmed => Ok(token),
+        _ => Err(Error::new("Issue not found")),
+    }
+}
+
+/// Compare the database's tables against what this version of the bot expects.
+pub fn check_schema(conn: &Connection) -> Result<()> {
+    let version = version(conn)?;
+    if version != MIGRATIONS.len() {
+        return Err(Error::new(format!(
+            "Database schema is version {}, expected {}.",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+    for (table, expected) in TABLES {
+        let mut stmt = conn.prepare("SELECT name FROM pragma_table_info(?)")?;
+        let columns = stmt
+            .query_map([table], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<String>, _>>()?;
+        if columns.is_empty() {
+            return Err(Error::new(format!("Table `{}` does not exist.", table)));
+        }
+        if columns != *expected {
+            return Err(Error::new(format!(
+                "Table `{}` has columns {:?}, expected {:?}.",
+                table, columns, expected
+            )));
+        }
+    }
+    let integrity: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    if integrity != "ok" {
+        return Err(Error::new(format!(
+            "Database integrity check failed: {}",
+            integrity
+        )));
+    }
+    Ok(())
+}
+
+/// A migrated in-memory database, for tests.
+#[cfg(test)]
+pub fn open_test() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+    init(&conn).unwrap();
+    conn
+}
+
+/// Track issue `id`, submitted by `submitter` and last updated at `last_update`, for tests.
+#[cfg(test)]
+pub fn insert_test_issue(conn: &Connection, id: i64, submitter: &str, last_update: &str) -> Issue {
+    conn.execute(
+        "INSERT INTO issue (id, submitter, password, time_created, anonymous, subscribed, title, last_update)
+            VALUES (?1, ?2, ?3, '2020-01-01T00:00:00Z', 0, 1, ?4, ?5)",
+        rusqlite::params![
+            id,
+            submitter,
+            Uuid::new_v4().as_bytes().to_vec(),
+            format!("Issue {}", id),
+            last_update
+        ],
+    )
+    .unwrap();
+    issue(conn, id).unwrap().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_in_memory() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+        conn
+    }
+
+    #[test]
+    fn fresh_database() {
+        let conn = open_in_memory();
+        init(&conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len());
+        check_schema(&conn).unwrap();
+    }
+
+    #[test]
+    fn legacy_database() {
+        let conn = open_in_memory();
+        // The schema and data of the releases before migrations.
+        conn.execute_batch(
+            r#"CREATE TABLE issue (
+                  id              INTEGER PRIMARY KEY,
+                  submitter       TEXT NOT NULL,
+                  password        BLOB,
+                  time_created    TEXT NOT NULL,
+                  anonymous       BOOLEAN,
+                  subscribed      BOOLEAN,
+                  title           TEXT NOT NULL,
+                  last_update     TEXT
+                  );"#,
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO issue VALUES (12, 'user@example.com', ?, '2020-01-01T00:00:00+00:00', \
+             0, 1, 'Crash on start', '\"2020-02-01T00:00:00+00:00\"')",
+            [Uuid::nil().as_bytes().to_vec()],
+        )
+        .unwrap();
+        init(&conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len());
+        check_schema(&conn).unwrap();
+        let issue = conn
+            .query_row("SELECT * FROM issue", [], issue_from_row)
+            .unwrap();
+        assert_eq!(issue.id, 12);
+        assert_eq!(issue.title, "Crash on start");
+        assert_eq!(issue.password, Uuid::nil());
+        assert_eq!(issue.last_update, "2020-02-01T00:00:00+00:00");
+        assert!(issue.subscribed && !issue.anonymous);
+        assert!(issue.muted.is_empty());
+        assert_eq!(issue.closed, None);
+    }
+
+    #[test]
+    fn init_twice() {
+        let conn = open_in_memory();
+        init(&conn).unwrap();
+        conn.execute_batch("INSERT INTO meta VALUES ('key', 'value');")
+            .unwrap();
+        init(&conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(meta(&conn, "key").unwrap().as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn newer_database() {
+        let conn = open_in_memory();
+        conn.execute_batch(&format!("PRAGMA user_version = {};", MIGRATIONS.len() + 1))
+            .unwrap();
+        assert!(init(&conn).is_err());
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len() + 1);
+    }
+
+    #[test]
+    fn failed_migration() {
+        fn broken(conn: &Connection) -> Result<()> {
+            conn.execute_batch("CREATE TABLE half (x); SELECT * FROM missing;")?;
+            Ok(())
+        }
+        let conn = open_in_memory();
+        let migrations = [
+            Migration {
+                name: "baseline",
+                run: baseline,
+            },
+            Migration {
+                name: "broken",
+                run: broken,
+            },
+        ];
+        let err = migrate(&conn, &migrations).unwrap_err();
+        assert!(err.to_string().starts_with("Migration 2 (broken) failed"));
+        // The first migration was committed, the second rolled back along with its version.
+        assert_eq!(version(&conn).unwrap(), 1);
+        let tables: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name IN ('issue', 'half')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 1);
+    }
+}
@@ -0,0 +1,417 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Digest delivery: instead of one notice per issue per cron run, updates are kept in the
+//! `pending_update` table and each subscriber gets at most one mail per period.
+
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Immediate,
+    Hourly,
+    Daily,
+    Weekly,
+}
+
+impl Delivery {
+    pub const ALL: [Delivery; 4] = [
+        Delivery::Immediate,
+        Delivery::Hourly,
+        Delivery::Daily,
+        Delivery::Weekly,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Delivery::Immediate => "immediate",
+            Delivery::Hourly => "hourly",
+            Delivery::Daily => "daily",
+            Delivery::Weekly => "weekly",
+        }
+    }
+
+    /// Minimum time between two digests, `None` for immediate delivery.
+    pub fn period(self) -> Option<chrono::Duration> {
+        match self {
+            Delivery::Immediate => None,
+            Delivery::Hourly => Some(chrono::Duration::hours(1)),
+            Delivery::Daily => Some(chrono::Duration::days(1)),
+            Delivery::Weekly => Some(chrono::Duration::weeks(1)),
+        }
+    }
+}
+
+impl std::str::FromStr for Delivery {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Delivery::ALL
+            .into_iter()
+            .find(|d| d.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                Error::new(format!(
+                    "`{}` is not a delivery mode. Valid modes are: {}",
+                    s,
+                    Delivery::ALL
+                        .iter()
+                        .map(|d| d.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                ))
+            })
+    }
+}
+
+pub fn delivery(conn: &Connection, address: &str) -> Result<Delivery> {
+    let mut stmt = conn.prepare("SELECT delivery FROM subscriber WHERE address = ?")?;
+    let mut results = stmt
+        .query_map([address], |row| row.get(0))?
+        .collect::<std::result::Result<Vec<String>, _>>()?;
+    match results.pop() {
+        Some(delivery) => delivery.parse(),
+        None => Ok(Delivery::Immediate),
+    }
+}
+
+/// Set the delivery mode of the subscriber `password` belongs to. It applies to every issue of
+/// that address.
+pub fn change_delivery(
+    conn: &Connection,
+    password: Password,
+    delivery: Delivery,
+) -> Result<(String, i64, String)> {
+    let db::Token {
+        issue_id,
+        title,
+        address,
+        ..
+    } = db::find_token(conn, password)?;
+    conn.execute(
+        "INSERT INTO subscriber (address, delivery) VALUES (?1, ?2)
+            ON CONFLICT(address) DO UPDATE SET delivery = excluded.delivery",
+        [&address, delivery.as_str()],
+    )?;
+    Ok((title, issue_id, address))
+}
+
+/// Keep an update for the next digest of `subscriber`, with its HTML version if they receive
+/// HTML.
+pub fn queue_update(
+    conn: &Connection,
+    subscriber: &db::Subscriber,
+    issue_id: i64,
+    body: &str,
+    html: Option<&str>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO pending_update (address, issue_id, body, time_created, password, html)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![
+            subscriber.address.to_string(),
+            issue_id,
+            body,
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            subscriber.password.as_bytes().to_vec(),
+            html
+        ],
+    )?;
+    Ok(())
+}
+
+/// An issue in a digest: its number, title, the subscriber's password and the updates.
+type DigestIssue = (i64, String, Password, Vec<String>);
+
+/// Group the updates of each issue together, in plain text and in HTML. `rows` are
+/// `(issue, title, password, body, update id, html)` ordered by issue.
+fn group(
+    rows: Vec<(i64, String, Password, String, i64, Option<String>)>,
+) -> (Vec<DigestIssue>, Vec<DigestIssue>) {
+    let mut issues: Vec<DigestIssue> = vec![];
+    let mut html_issues: Vec<DigestIssue> = vec![];
+    for (issue_id, title, password, body, _, html) in rows {
+        match issues.last_mut() {
+            Some(last) if last.0 == issue_id => last.3.push(body),
+            _ => issues.push((issue_id, title.clone(), password, vec![body])),
+        }
+        match html_issues.last_mut() {
+            Some(last) if last.0 == issue_id => last.3.extend(html),
+            _ => html_issues.push((issue_id, title, password, html.into_iter().collect())),
+        }
+    }
+    (issues, html_issues)
+}
+
+fn send_digest(conn: &Connection, conf: &Configuration, address: &str) -> Result<()> {
+    // Updates queued by another process while the digest is built are left for the next one.
+    let tx = db::write_transaction(conn)?;
+    let mut stmt = tx.prepare(
+        "SELECT issue.id, issue.title, COALESCE(pending_update.password, issue.password),
+                pending_update.body, pending_update.id, pending_update.html
+            FROM pending_update JOIN issue ON issue.id = pending_update.issue_id
+            WHERE pending_update.address = ?
+            ORDER BY issue.id, pending_update.id",
+    )?;
+    let rows = stmt
+        .query_map([address], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
+        })?
+        .collect::<std::result::Result<
+            Vec<(i64, String, Password, String, i64, Option<String>)>,
+            _,
+        >>()?;
+    drop(stmt);
+    let ids = rows.iter().map(|r| r.4).collect::<Vec<i64>>();
+    // Updates queued before the subscriber switched to HTML have no HTML version, and then the
+    // digest is sent as plain text.
+    let with_html = db::html(&tx, address)? && rows.iter().all(|r| r.5.is_some());
+    let (issues, html_issues) = group(rows);
+    let mut queued = vec![];
+    if !issues.is_empty() {
+        let lang = db::language(&tx, address)?;
+        let mut notice = melib::Draft::default();
+        notice.headers_mut().insert(
+            HeaderName::new_unchecked("From"),
+            format!(
+                "{local_part}@{domain}",
+                local_part = &conf.local_part,
+                domain = &conf.domain
+            ),
+        );
+        notice.headers_mut().insert(
+            HeaderName::new_unchecked("Subject"),
+            templates::subject(
+                "digest",
+                context! { count => issues.len() },
+                lang.as_deref(),
+                conf,
+            )?,
+        );
+        notice
+            .headers_mut()
+            .insert(HeaderName::new_unchecked("To"), address.to_string());
+        notice.set_body(templates::digest(lang.as_deref(), conf, &issues)?);
+        let html = if with_html {
+            Some(templates::digest_html(lang.as_deref(), conf, &html_issues)?)
+        } else {
+            None
+        };
+        queued.extend(queue_mail(&tx, notice, html.as_deref(), conf)?);
+    }
+    if !conf.dry_run {
+        for id in ids {
+            tx.execute("DELETE FROM pending_update WHERE id = ?", [id])?;
+        }
+        tx.execute(
+            "UPDATE subscriber SET last_digest = ? WHERE address = ?",
+            [
+                &chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                address,
+            ],
+        )?;
+    }
+    tx.commit()?;
+    deliver_queued(conn, conf, &queued);
+    Ok(())
+}
+
+/// Whether a subscriber with `delivery` who got their last digest at `last_digest` is due
+/// another one at `now`.
+fn is_due(
+    delivery: Option<&str>,
+    last_digest: Option<&str>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<bool> {
+    let delivery: Delivery = match delivery {
+        Some(delivery) => delivery.parse()?,
+        None => Delivery::Immediate,
+    };
+    Ok(match (delivery.period(), last_digest) {
+        (Some(period), Some(last_digest)) => {
+            let last_digest = chrono::DateTime::parse_from_rfc3339(last_digest)
+                .map_err(|err| Error::new(err.to_string()))?;
+            now.signed_duration_since(last_digest) >= period
+        }
+        // Switched back to immediate delivery with updates still pending, or first digest.
+        _ => true,
+    })
+}
+
+/// Mail a digest to every subscriber whose period has elapsed. Returns how many were sent. A
+/// subscriber whose digest fails is logged and skipped, and tried again on the next run.
+pub fn send_due(conn: &Connection, conf: &Configuration) -> Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT pending_update.address, subscriber.delivery, subscriber.last_digest
+            FROM pending_update LEFT JOIN subscriber
+            ON subscriber.address = pending_update.address",
+    )?;
+    let subscribers = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<std::result::Result<Vec<(String, Option<String>, Option<String>)>, _>>()?;
+    let now = chrono::Utc::now();
+    let mut sent = 0;
+    for (address, delivery, last_digest) in subscribers {
+        let result = is_due(delivery.as_deref(), last_digest.as_deref(), now).and_then(|due| {
+            if due {
+                send_digest(conn, conf, &address)?;
+            }
+            Ok(due)
+        });
+        match result {
+            Ok(true) => sent += 1,
+            Ok(false) => {}
+            Err(err) => error!("Could not send the digest of {}: {}", address, err),
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(conn: &Connection, address: &str, issue: &Issue, body: &str, html: Option<&str>) {
+        conn.execute(
+            "INSERT INTO pending_update (address, issue_id, body, time_created, password, html)
+                VALUES (?1, ?2, ?3, '2024-01-01T00:00:00Z', ?4, ?5)",
+            rusqlite::params![
+                address,
+                issue.id,
+                body,
+                issue.password.as_bytes().to_vec(),
+                html
+            ],
+        )
+        .unwrap();
+    }
+
+    fn ago(hours: i64) -> String {
+        (chrono::Utc::now() - chrono::Duration::hours(hours))
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    }
+
+    #[test]
+    fn due() {
+        let now = chrono::Utc::now();
+        assert!(is_due(None, None, now).unwrap());
+        assert!(is_due(Some("immediate"), Some(&ago(0)), now).unwrap());
+        assert!(is_due(Some("daily"), None, now).unwrap());
+        assert!(!is_due(Some("daily"), Some(&ago(23)), now).unwrap());
+        assert!(is_due(Some("daily"), Some(&ago(24)), now).unwrap());
+        assert!(is_due(Some("hourly"), Some(&ago(1)), now).unwrap());
+        assert!(!is_due(Some("weekly"), Some(&ago(24 * 6)), now).unwrap());
+        assert!(is_due(Some("fortnightly"), None, now).is_err());
+        assert!(is_due(Some("daily"), Some("yesterday"), now).is_err());
+    }
+
+    #[test]
+    fn send_due_digests() {
+        let conn = db::open_test();
+        let mut conf = conf::example();
+        conf.mailer = Some("true".to_string());
+        templates::load(&conf).unwrap();
+        let issue = db::insert_test_issue(&conn, 1, "jane@example.com", "2024-01-01T00:00:00Z");
+        conn.execute_batch(&format!(
+            "INSERT INTO subscriber (address, delivery, last_digest) VALUES
+                ('daily@example.com', 'daily', '{}'),
+                ('hourly@example.com', 'hourly', '{}'),
+                ('broken@example.com', 'daily', 'yesterday');",
+            ago(1),
+            ago(2)
+        ))
+        .unwrap();
+        for address in [
+            "daily@example.com",
+            "hourly@example.com",
+            "broken@example.com",
+            "immediate@example.com",
+        ] {
+            pending(&conn, address, &issue, "Closed.", None);
+        }
+        // The broken subscriber is skipped without stopping the others.
+        assert_eq!(send_due(&conn, &conf).unwrap(), 2);
+        let mut stmt = conn
+            .prepare("SELECT address FROM pending_update ORDER BY address")
+            .unwrap();
+        let left = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .unwrap();
+        assert_eq!(left, ["broken@example.com", "daily@example.com"]);
+        let last_digest: String = conn
+            .query_row(
+                "SELECT last_digest FROM subscriber WHERE address = 'hourly@example.com'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(last_digest > ago(1));
+        // Nothing is due any more.
+        assert_eq!(send_due(&conn, &conf).unwrap(), 0);
+    }
+
+    #[test]
+    fn group_by_issue() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let row = |issue: i64, password: Password, body: &str, html: Option<&str>| {
+            (
+                issue,
+                format!("Issue {}", issue),
+                password,
+                body.to_string(),
+                0,
+                html.map(str::to_string),
+            )
+        };
+        let (issues, html_issues) = group(vec![
+            row(1, a, "one", Some("<p>one</p>")),
+            row(1, a, "two", None),
+            row(2, b, "three", Some("<p>three</p>")),
+        ]);
+        assert_eq!(
+            issues,
+            [
+                (
+                    1,
+                    "Issue 1".to_string(),
+                    a,
+                    vec!["one".to_string(), "two".to_string()]
+                ),
+                (2, "Issue 2".to_string(), b, vec!["three".to_string()]),
+            ]
+        );
+        assert_eq!(
+            html_issues,
+            [
+                (1, "Issue 1".to_string(), a, vec!["<p>one</p>".to_string()]),
+                (
+                    2,
+                    "Issue 2".to_string(),
+                    b,
+                    vec!["<p>three</p>".to_string()]
+                ),
+            ]
+        );
+    }
+}
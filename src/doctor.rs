@@ -0,0 +1,161 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::*;
+
+/// Oldest Gitea release with `since` filtering and `X-Total-Count`/`Link` pagination headers on
+/// the endpoints we use, and the issue timeline endpoint.
+const MIN_GITEA_VERSION: (u64, u64) = (1, 15);
+
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn check(&mut self, what: &str, result: Result<String>) {
+        match result {
+            Ok(details) if details.is_empty() => println!("[PASS] {}", what),
+            Ok(details) => println!("[PASS] {}: {}", what, details),
+            Err(err) => {
+                self.failures += 1;
+                println!("[FAIL] {}: {}", what, err);
+            }
+        }
+    }
+}
+
+fn check_version(conf: &Configuration) -> Result<String> {
+    let version = api::version(conf)?;
+    // Versions look like "1.17.3" or "1.18.0+rc1-12-gabcdef".
+    let mut numbers = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u64>().unwrap_or(0));
+    let found = (numbers.next().unwrap_or(0), numbers.next().unwrap_or(0));
+    if found < MIN_GITEA_VERSION {
+        return Err(Error::new(format!(
+            "Gitea {} is older than the minimum supported {}.{}",
+            version, MIN_GITEA_VERSION.0, MIN_GITEA_VERSION.1
+        )));
+    }
+    Ok(format!("Gitea {}", version))
+}
+
+fn check_user(conf: &Configuration) -> Result<String> {
+    let user = api::current_user(conf)?;
+    let login = gitea_api_mismatch!(user["login"].as_str());
+    if login != conf.bot_username {
+        return Err(Error::new(format!(
+            "token belongs to `{}` but bot_username is `{}`; replies by the bot would be shown to subscribers as replies by another user",
+            login, conf.bot_username
+        )));
+    }
+    Ok(format!("authenticated as {}", login))
+}
+
+fn check_repository(conf: &Configuration) -> Result<String> {
+    let repo = api::repository(conf)?;
+    if repo["has_issues"] == false {
+        return Err(Error::new(format!(
+            "the issue tracker of {} is disabled",
+            conf.repo
+        )));
+    }
+    // Only present when the request is authenticated; `push` is what closing and editing issues
+    // takes.
+    let permissions = &repo["permissions"];
+    if permissions["push"] != true && permissions["admin"] != true {
+        return Err(Error::new(format!(
+            "the token has no write access to {}",
+            conf.repo
+        )));
+    }
+    Ok(format!("can write to {}", conf.repo))
+}
+
+fn check_mailer(conf: &Configuration) -> Result<String> {
+    let command = match conf.transport()? {
+        transport::Transport::Sendmail(command) => command,
+        transport::Transport::Smtp(server) => {
+            transport::smtp_probe(server)?;
+            return Ok(format!("connected to {}:{}", server.hostname, server.port));
+        }
+    };
+    let parts = transport::split_command(command)?;
+    let cmd = parts[0].as_str();
+    let path = std::path::Path::new(cmd);
+    let found = if cmd.contains('/') {
+        path.is_file().then(|| path.to_path_buf())
+    } else {
+        std::env::var_os("PATH").and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(cmd))
+                .find(|p| p.is_file())
+        })
+    };
+    let found = found.ok_or_else(|| Error::new(format!("`{}` not found", cmd)))?;
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if std::fs::metadata(&found)?.permissions().mode() & 0o111 == 0 {
+            return Err(Error::new(format!("{} is not executable", found.display())));
+        }
+    }
+    Ok(found.display().to_string())
+}
+
+fn send_test_mail(conf: &Configuration, to: Address) -> Result<String> {
+    let mut mail = melib::Draft::default();
+    mail.headers_mut().insert(
+        HeaderName::new_unchecked("From"),
+        format!(
+            "{local_part}@{domain}",
+            local_part = &conf.local_part,
+            domain = &conf.domain
+        ),
+    );
+    mail.headers_mut()
+        .insert(HeaderName::new_unchecked("To"), to.to_string());
+    mail.headers_mut().insert(
+        HeaderName::new_unchecked("Subject"),
+        templates::subject("test_mail", context! {}, None, conf)?,
+    );
+    mail.set_body(templates::test_mail(None)?);
+    if !conf.dry_run {
+        // Bypass the queue: we want to see whether the mailer works right now.
+        transport::deliver(&mail::finalise(mail, None, conf)?, conf)?;
+    }
+    Ok(format!("sent to {}", to))
+}
+
+/// Check the configuration end to end and print a pass/fail line for each step.
+pub fn run(conn: &Connection, conf: &Configuration, test_mail: Option<Address>) -> Result<()> {
+    let mut report = Report { failures: 0 };
+    report.check("Gitea version", check_version(conf));
+    report.check("Auth token", check_user(conf));
+    report.check("Repository access", check_repository(conf));
+    report.check(
+        "Database schema",
+        db::check_schema(conn).map(|()| String::new()),
+    );
+    report.check("Mailer", check_mailer(conf));
+    if let Some(to) = test_mail {
+        report.check("Test mail", send_test_mail(conf, to));
+    }
+    if report.failures > 0 {
+        return Err(Error::new(format!("{} check(s) failed.", report.failures)));
+    }
+    println!("All checks passed.");
+    Ok(())
+}
@@ -0,0 +1,254 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `+forget`: erasing a reporter's address from the database and from the issues and comments
+//! the bot posted on Gitea on their behalf.
+
+use super::*;
+
+/// What [`forget`] erased, for the confirmation sent back.
+pub struct Erased {
+    /// Issues submitted from the address, number and title.
+    pub issues: Vec<(i64, String)>,
+    /// Subscriptions to other people's issues.
+    pub subscriptions: usize,
+    /// Issue bodies and comments on Gitea whose author was replaced by "Anonymous".
+    pub posts: usize,
+    /// URLs of the issues and comments that could not be rewritten and still show the address.
+    pub failed: Vec<String>,
+}
+
+/// The bare, lowercased address of `address`, eg. `user@example.com` for
+/// `Name <User@example.com>`.
+fn mailbox(address: &str) -> String {
+    let address = address.trim();
+    match (address.rfind('<'), address.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &address[start + 1..end],
+        _ => address,
+    }
+    .trim()
+    .to_lowercase()
+}
+
+/// `body` with its author made anonymous, if it was posted by the bot for `owner`: issues start
+/// with `{address} reports:` and comments with `{address} replies:`.
+fn anonymise(body: &str, owner: &str) -> Option<String> {
+    let (first, rest) = body.split_once("\n\n")?;
+    let (identity, verb) = ["reports", "replies"]
+        .into_iter()
+        .find_map(|verb| Some((first.strip_suffix(&format!(" {}:", verb))?, verb)))?;
+    if mailbox(identity) != owner {
+        return None;
+    }
+    Some(format!("Anonymous {}:\n\n{}", verb, rest))
+}
+
+/// Make the bot's posts for `owner` on issue `id` anonymous. Returns how many were rewritten and
+/// the URLs of those that could not be, after logging why.
+fn anonymise_issue(id: i64, owner: &str, conf: &Configuration) -> Result<(usize, Vec<String>)> {
+    let (mut posts, mut failed) = (0, vec![]);
+    let issue = api::issue(id, conf)?;
+    let user = &issue["user"];
+    if gitea_api_mismatch!(user["login"].as_str()) == conf.bot_username {
+        if let Some(body) = anonymise(gitea_api_mismatch!(issue["body"].as_str()), owner) {
+            if conf.dry_run {
+                println!("Would anonymise the body of issue #{}", id);
+                posts += 1;
+            } else {
+                match api::edit_issue_body(id, body, conf) {
+                    Ok(_) => posts += 1,
+                    Err(err) => {
+                        error!("Could not anonymise the body of issue #{}: {}", id, err);
+                        failed.push(templates::issue_url(id, conf));
+                    }
+                }
+            }
+        }
+    }
+    for comment in api::comments(id, "1970-01-01T00:00:00Z", conf)? {
+        let user = &comment["user"];
+        if gitea_api_mismatch!(user["login"].as_str()) != conf.bot_username {
+            continue;
+        }
+        if let Some(body) = anonymise(gitea_api_mismatch!(comment["body"].as_str()), owner) {
+            let comment_id = gitea_api_mismatch!(comment["id"].as_i64());
+            if conf.dry_run {
+                println!("Would anonymise comment {} on issue #{}", comment_id, id);
+                posts += 1;
+            } else {
+                match api::edit_comment(comment_id, body, conf) {
+                    Ok(_) => posts += 1,
+                    Err(err) => {
+                        error!(
+                            "Could not anonymise comment {} on issue #{}: {}",
+                            comment_id, id, err
+                        );
+                        failed.push(format!(
+                            "{}#issuecomment-{}",
+                            templates::issue_url(id, conf),
+                            comment_id
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    Ok((posts, failed))
+}
+
+/// Erase the owner of `password`: rewrite what the bot posted on Gitea for them, then delete
+/// their address from every table. Posts that can't be rewritten don't stop the rest: they are
+/// logged and listed in [`Erased::failed`] for the operators to edit by hand.
+pub fn forget(conn: &Connection, password: Password, conf: &Configuration) -> Result<Erased> {
+    let token = db::find_token(conn, password)?;
+    let owner = mailbox(&token.address);
+    let mut erased = Erased {
+        issues: vec![],
+        subscriptions: 0,
+        posts: 0,
+        failed: vec![],
+    };
+    let mut touched = vec![];
+    for issue in db::issues(conn)? {
+        if mailbox(&issue.submitter.to_string()) == owner {
+            erased.issues.push((issue.id, issue.title.clone()));
+            touched.push(issue.id);
+        } else if db::subscriptions(conn, issue.id)?
+            .iter()
+            .any(|s| mailbox(&s.address) == owner)
+        {
+            erased.subscriptions += 1;
+            touched.push(issue.id);
+        }
+    }
+    for &id in &touched {
+        match anonymise_issue(id, &owner, conf) {
+            Ok((posts, failed)) => {
+                erased.posts += posts;
+                erased.failed.extend(failed);
+            }
+            Err(err) => {
+                error!("Could not anonymise the posts on issue #{}: {}", id, err);
+                erased.failed.push(templates::issue_url(id, conf));
+            }
+        }
+    }
+    let addresses = db::addresses(conn)?
+        .into_iter()
+        .filter(|a| mailbox(a) == owner)
+        .collect::<Vec<String>>();
+    let submitted = erased
+        .issues
+        .iter()
+        .map(|(id, _)| *id)
+        .collect::<Vec<i64>>();
+    if conf.dry_run {
+        println!(
+            "Would erase {} from the database, and issues {:?}",
+            addresses.join(", "),
+            submitted
+        );
+    } else {
+        db::erase(conn, &addresses, &submitted)?;
+    }
+    info!(
+        "Erased the address of issues {:?} and {} subscriptions on request",
+        submitted, erased.subscriptions
+    );
+    Ok(erased)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mailbox_ignores_name_and_case() {
+        assert_eq!(mailbox("user@example.com"), "user@example.com");
+        assert_eq!(mailbox(" Jane Doe <Jane@Example.com> "), "jane@example.com");
+        assert_eq!(
+            mailbox("\"Doe, <Jane>\" <jane@example.com>"),
+            "jane@example.com"
+        );
+        assert_eq!(mailbox("<jane@example.com"), "<jane@example.com");
+    }
+
+    #[test]
+    fn anonymise_own_posts() {
+        let owner = "jane@example.com";
+        assert_eq!(
+            anonymise(
+                "Jane <JANE@example.com> reports:\n\nIt crashes.\n\nTwice.",
+                owner
+            )
+            .as_deref(),
+            Some("Anonymous reports:\n\nIt crashes.\n\nTwice.")
+        );
+        assert_eq!(
+            anonymise("jane@example.com replies:\n\nStill there.", owner).as_deref(),
+            Some("Anonymous replies:\n\nStill there.")
+        );
+    }
+
+    #[test]
+    fn anonymise_leaves_other_posts() {
+        let owner = "jane@example.com";
+        assert_eq!(
+            anonymise("john@example.com replies:\n\nMe too.", owner),
+            None
+        );
+        assert_eq!(anonymise("Anonymous replies:\n\nMe too.", owner), None);
+        assert_eq!(anonymise("jane@example.com replies: hi", owner), None);
+        assert_eq!(anonymise("jane@example.com says:\n\nhi", owner), None);
+        assert_eq!(
+            anonymise("Quoting jane@example.com reports:\n\nhi", owner),
+            None
+        );
+    }
+
+    #[test]
+    fn forget_leaves_nothing_queued() {
+        let conn = db::open_test();
+        let mut conf = conf::example();
+        // A failed delivery is what would leave the confirmation, and the address, queued.
+        conf.mailer = Some("false".to_string());
+        // Gitea can't be reached: the posts are listed as failed and the rest carries on.
+        conf.base_url = "http://127.0.0.1:1".to_string();
+        conf.http.retries = 0;
+        templates::load(&conf).unwrap();
+        let issue = db::insert_test_issue(&conn, 1, "jane@example.com", "2024-01-01T00:00:00Z");
+        queue::enqueue(&conn, "jane@example.com", "Re: Issue 1", "Hello").unwrap();
+
+        let erased = forget(&conn, issue.password, &conf).unwrap();
+        let mut reply = melib::email::Draft::default();
+        reply.headers_mut().insert(
+            HeaderName::new_unchecked("To"),
+            "jane@example.com".to_string(),
+        );
+        reply.set_body(templates::forget_success(&erased, None, &conf).unwrap());
+        send_mail_unqueued(reply, &conf).unwrap();
+
+        let left: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM queue WHERE recipient LIKE '%jane@example.com%'
+                    OR message LIKE '%jane@example.com%'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+}
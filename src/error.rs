@@ -26,6 +26,8 @@ error_chain! {
         Conf(toml::de::Error);
         Logger(log::SetLoggerError);
         Password(uuid::Error);
+        ParseInt(std::num::ParseIntError);
+        Template(minijinja::Error);
    }
 }
 
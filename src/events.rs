@@ -0,0 +1,323 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Issue changes other than comments, as reported by Gitea's issue timeline.
+
+use super::*;
+
+/// The kinds of change a subscriber can mute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    State,
+    Labels,
+    Assignees,
+    Milestone,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 4] = [
+        EventKind::State,
+        EventKind::Labels,
+        EventKind::Assignees,
+        EventKind::Milestone,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::State => "state",
+            EventKind::Labels => "labels",
+            EventKind::Assignees => "assignees",
+            EventKind::Milestone => "milestone",
+        }
+    }
+
+    /// Parse a space separated list as stored in the `muted` column.
+    pub fn parse_list(s: &str) -> Vec<EventKind> {
+        s.split_whitespace()
+            .filter_map(|k| k.parse().ok())
+            .collect()
+    }
+
+    pub fn join_list(kinds: &[EventKind]) -> String {
+        kinds
+            .iter()
+            .map(|k| k.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+impl std::str::FromStr for EventKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        EventKind::ALL
+            .into_iter()
+            .find(|k| k.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                Error::new(format!(
+                    "`{}` is not a kind of change. Valid kinds are: {}",
+                    s,
+                    EventKind::join_list(&EventKind::ALL)
+                ))
+            })
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    State {
+        user: String,
+        closed: bool,
+    },
+    Label {
+        user: String,
+        label: String,
+        added: bool,
+    },
+    Assignee {
+        user: String,
+        assignee: String,
+        removed: bool,
+    },
+    /// `milestone` is `None` when the issue was removed from its milestone.
+    Milestone {
+        user: String,
+        milestone: Option<String>,
+    },
+}
+
+/// The login of a timeline entry's user, or Gitea's own placeholder for a user that no longer
+/// exists or was never set, eg. on entries made by the system.
+fn login(user: &serde_json::Value) -> String {
+    user["login"].as_str().unwrap_or("Ghost").to_string()
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::State { .. } => EventKind::State,
+            Event::Label { .. } => EventKind::Labels,
+            Event::Assignee { .. } => EventKind::Assignees,
+            Event::Milestone { .. } => EventKind::Milestone,
+        }
+    }
+
+    /// Interpret an entry of `GET /repos/{repo}/issues/{index}/timeline`. Returns `None` for
+    /// entries we don't notify about, such as comments (handled separately) or commit references.
+    pub fn from_timeline(
+        c: &serde_json::map::Map<String, serde_json::Value>,
+    ) -> Result<Option<Event>> {
+        let kind = gitea_api_mismatch!(c["type"].as_str());
+        if !["close", "reopen", "label", "assignees", "milestone"].contains(&kind) {
+            return Ok(None);
+        }
+        let user = login(&c["user"]);
+        Ok(Some(match kind {
+            "close" => Event::State { user, closed: true },
+            "reopen" => Event::State {
+                user,
+                closed: false,
+            },
+            "label" => {
+                let l = &c["label"];
+                Event::Label {
+                    user,
+                    label: gitea_api_mismatch!(l["name"].as_str()).to_string(),
+                    // Gitea stores "1" in the body of a label comment when the label was added.
+                    added: c["body"] == "1",
+                }
+            }
+            "assignees" => Event::Assignee {
+                user,
+                assignee: login(&c["assignee"]),
+                removed: c["removed_assignee"].as_bool().unwrap_or(false),
+            },
+            "milestone" => {
+                let m = &c["milestone"];
+                Event::Milestone {
+                    user,
+                    milestone: m["title"].as_str().map(str::to_string),
+                }
+            }
+            _ => return Ok(None),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// An entry of Gitea's issue timeline, with the fields every entry has, `user` as `jane` and
+    /// `extra` fields on top.
+    fn entry(
+        kind: &str,
+        extra: serde_json::Value,
+    ) -> serde_json::map::Map<String, serde_json::Value> {
+        let mut entry = json!({
+            "id": 4321,
+            "type": kind,
+            "html_url": "",
+            "pull_request_url": "",
+            "issue_url": "https://git.meli.delivery/meli/meli/issues/12",
+            "user": {
+                "id": 2,
+                "login": "jane",
+                "full_name": "",
+                "email": "jane@noreply.meli.delivery",
+                "avatar_url": "https://git.meli.delivery/avatars/abc"
+            },
+            "body": "",
+            "created_at": "2024-01-02T10:00:00+01:00",
+            "updated_at": "2024-01-02T10:00:00+01:00",
+            "label": null,
+            "assignee": null,
+            "assignee_team": null,
+            "removed_assignee": false,
+            "milestone": null,
+            "old_milestone": null,
+            "ref_issue": null,
+            "ref_comment": null,
+            "ref_action": "",
+            "ref_commit_sha": ""
+        });
+        for (key, value) in extra.as_object().unwrap() {
+            entry[key] = value.clone();
+        }
+        serde_json::from_value(entry).unwrap()
+    }
+
+    fn event(kind: &str, extra: serde_json::Value) -> Option<Event> {
+        Event::from_timeline(&entry(kind, extra)).unwrap()
+    }
+
+    fn label(name: &str) -> serde_json::Value {
+        json!({
+            "id": 7,
+            "name": name,
+            "exclusive": false,
+            "is_archived": false,
+            "color": "ee0701",
+            "description": "Something isn't working",
+            "url": "https://git.meli.delivery/api/v1/repos/meli/meli/labels/7"
+        })
+    }
+
+    fn milestone(title: &str) -> serde_json::Value {
+        json!({
+            "id": 3,
+            "title": title,
+            "description": "",
+            "state": "open",
+            "open_issues": 4,
+            "closed_issues": 10,
+            "created_at": "2023-06-01T00:00:00Z",
+            "updated_at": "2024-01-02T09:00:00Z",
+            "closed_at": null,
+            "due_on": null
+        })
+    }
+
+    #[test]
+    fn close_and_reopen() {
+        assert!(matches!(
+            event("close", json!({})),
+            Some(Event::State { user, closed: true }) if user == "jane"
+        ));
+        assert!(matches!(
+            event("reopen", json!({})),
+            Some(Event::State { user, closed: false }) if user == "jane"
+        ));
+    }
+
+    #[test]
+    fn labels() {
+        assert!(matches!(
+            event("label", json!({"body": "1", "label": label("bug")})),
+            Some(Event::Label { user, label, added: true }) if user == "jane" && label == "bug"
+        ));
+        assert!(matches!(
+            event("label", json!({"body": "", "label": label("needs info")})),
+            Some(Event::Label { label, added: false, .. }) if label == "needs info"
+        ));
+        assert!(Event::from_timeline(&entry("label", json!({"body": "1"}))).is_err());
+    }
+
+    #[test]
+    fn assignees() {
+        let john = json!({"id": 3, "login": "john", "full_name": "John", "email": ""});
+        assert!(matches!(
+            event("assignees", json!({"assignee": john})),
+            Some(Event::Assignee { user, assignee, removed: false })
+                if user == "jane" && assignee == "john"
+        ));
+        assert!(matches!(
+            event("assignees", json!({"assignee": john, "removed_assignee": true})),
+            Some(Event::Assignee { assignee, removed: true, .. }) if assignee == "john"
+        ));
+    }
+
+    #[test]
+    fn milestones() {
+        assert!(matches!(
+            event("milestone", json!({"milestone": milestone("0.8.5")})),
+            Some(Event::Milestone { user, milestone: Some(m) }) if user == "jane" && m == "0.8.5"
+        ));
+        // Removed from its milestone: only `old_milestone` is set.
+        assert!(matches!(
+            event("milestone", json!({"old_milestone": milestone("0.8.5")})),
+            Some(Event::Milestone {
+                milestone: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn deleted_users() {
+        // Gitea replaces deleted users with its ghost user, or leaves them out.
+        let ghost = json!({"id": -1, "login": "Ghost", "full_name": "", "email": ""});
+        assert!(matches!(
+            event("close", json!({"user": ghost})),
+            Some(Event::State { user, .. }) if user == "Ghost"
+        ));
+        assert!(matches!(
+            event("close", json!({"user": null})),
+            Some(Event::State { user, .. }) if user == "Ghost"
+        ));
+        assert!(matches!(
+            event("assignees", json!({"assignee": null, "removed_assignee": true})),
+            Some(Event::Assignee { assignee, .. }) if assignee == "Ghost"
+        ));
+    }
+
+    #[test]
+    fn not_notified() {
+        assert!(event("comment", json!({"body": "Same here."})).is_none());
+        assert!(event(
+            "commit_ref",
+            json!({"body": "<a href=\"https://git.meli.delivery/meli/meli/commit/abc\">fix</a>"})
+        )
+        .is_none());
+        assert!(event("change_title", json!({"old_title": "a", "new_title": "b"})).is_none());
+        assert!(event("pin", json!({})).is_none());
+        let mut untyped = entry("close", json!({}));
+        untyped["type"] = serde_json::Value::Null;
+        assert!(Event::from_timeline(&untyped).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `issue-bot import`: tracking issues that were reported before the bot was deployed, on Gitea
+//! or elsewhere, so that their reporters can manage them by email.
+
+use super::*;
+use serde::Deserialize;
+
+static USAGE: &str = "Usage: issue_bot import (--issue <number> --submitter <address> [--anonymous] | --from <file>) [--notify]";
+
+/// An issue to import and the address of its reporter. Bulk imports are a JSON array of these or
+/// a CSV file with an `issue,submitter[,anonymous]` header.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Mapping {
+    issue: i64,
+    submitter: String,
+    /// Post the reporter's replies without their address.
+    #[serde(default)]
+    anonymous: bool,
+}
+
+/// Split a CSV line into fields. Fields may be quoted, eg. `"Doe, Jane <jane@example.com>"`, with
+/// `""` for a quote inside.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.into_iter().map(|f| f.trim().to_string()).collect()
+}
+
+fn read_mappings(path: &str) -> Result<Vec<Mapping>> {
+    let contents = std::fs::read_to_string(path)?;
+    if contents.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(&contents)?);
+    }
+    // Numbered before blank lines are skipped, so that errors point at the right line.
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty());
+    let header = csv_fields(lines.next().map(|(_, l)| l).unwrap_or_default());
+    let column = |name: &str| header.iter().position(|h| h.eq_ignore_ascii_case(name));
+    let (Some(issue), Some(submitter)) = (column("issue"), column("submitter")) else {
+        return Err(Error::new(format!(
+            "{}: expected a CSV header with `issue` and `submitter` columns.",
+            path
+        )));
+    };
+    let anonymous = column("anonymous");
+    lines
+        .map(|(i, line)| {
+            let fields = csv_fields(line);
+            let field = |n: usize| fields.get(n).map(String::as_str).unwrap_or_default();
+            Ok(Mapping {
+                issue: field(issue).parse().map_err(|_| {
+                    Error::new(format!(
+                        "{}:{}: invalid issue number `{}`.",
+                        path,
+                        i + 1,
+                        field(issue)
+                    ))
+                })?,
+                submitter: field(submitter).to_string(),
+                anonymous: anonymous
+                    .map(|n| matches!(field(n).to_lowercase().as_str(), "1" | "true" | "yes"))
+                    .unwrap_or(false),
+            })
+        })
+        .collect()
+}
+
+/// Start tracking Gitea issue `issue_id` for `submitter`, with a fresh password. Only activity
+/// after the import is notified. Returns the issue's title and the password.
+pub fn import_issue(
+    conn: &Connection,
+    conf: &Configuration,
+    issue_id: i64,
+    submitter: &Address,
+    anonymous: bool,
+) -> Result<(String, Password)> {
+    if db::issue(conn, issue_id)?.is_some() {
+        return Err(Error::new(format!(
+            "Issue {} is already tracked.",
+            issue_id
+        )));
+    }
+    let map = api::issue(issue_id, conf)?;
+    if map.get("pull_request").is_some_and(|p| !p.is_null()) {
+        return Err(Error::new(format!("#{} is a pull request.", issue_id)));
+    }
+    let title = gitea_api_mismatch!(map["title"].as_str()).to_string();
+    let closed = gitea_api_mismatch!(map["state"].as_str()) == "closed";
+    let password = Uuid::new_v4();
+    let tx = db::write_transaction(conn)?;
+    tx.execute(
+        "INSERT INTO issue (id, submitter, password, time_created, anonymous, subscribed, title, last_update)
+            VALUES (?1, ?2, ?3, ?4, ?5, 1, ?6, ?7)",
+        rusqlite::params![
+            issue_id,
+            submitter.to_string(),
+            password.as_bytes().to_vec(),
+            chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            anonymous,
+            &title,
+            gitea_api_mismatch!(map["updated_at"].as_str()),
+        ],
+    )?;
+    db::set_state(&tx, issue_id, closed, map["closed_at"].as_str())?;
+    tx.commit()?;
+    Ok((title, password))
+}
+
+/// Mail `submitter` the password of an imported issue.
+fn notify(
+    conn: &Connection,
+    conf: &Configuration,
+    mapping: &Mapping,
+    title: &str,
+    password: Password,
+) -> Result<()> {
+    let lang = db::language(conn, &mapping.submitter)?;
+    let mut notice = melib::Draft::default();
+    notice.headers_mut().insert(
+        HeaderName::new_unchecked("From"),
+        format!(
+            "{local_part}@{domain}",
+            local_part = &conf.local_part,
+            domain = &conf.domain
+        ),
+    );
+    notice
+        .headers_mut()
+        .insert(HeaderName::new_unchecked("To"), mapping.submitter.clone());
+    notice.headers_mut().insert(
+        HeaderName::new_unchecked("Subject"),
+        templates::subject("import_success", context! { title }, lang.as_deref(), conf)?,
+    );
+    notice.set_body(templates::import_success(
+        title,
+        password,
+        mapping.issue,
+        mapping.anonymous,
+        lang.as_deref(),
+        conf,
+    )?);
+    send_mail(conn, notice, conf)
+}
+
+/// `issue-bot import (--issue <number> --submitter <address> [--anonymous] | --from <file>) [--notify]`
+pub fn run(conn: &Connection, conf: &Configuration, args: &[&str]) -> Result<()> {
+    let (mut issue, mut submitter, mut anonymous, mut from, mut notify_submitters) =
+        (None, None, false, None, false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--issue" => {
+                issue = Some(
+                    args.next()
+                        .ok_or_else(|| Error::new(USAGE))?
+                        .parse::<i64>()?,
+                )
+            }
+            "--submitter" => submitter = Some(*args.next().ok_or_else(|| Error::new(USAGE))?),
+            "--anonymous" => anonymous = true,
+            "--from" => from = Some(*args.next().ok_or_else(|| Error::new(USAGE))?),
+            "--notify" => notify_submitters = true,
+            _ => return Err(Error::new(USAGE)),
+        }
+    }
+    let mappings = match (issue, submitter, from) {
+        (Some(issue), Some(submitter), None) => vec![Mapping {
+            issue,
+            submitter: submitter.to_string(),
+            anonymous,
+        }],
+        (None, None, Some(path)) if !anonymous => read_mappings(path)?,
+        _ => return Err(Error::new(USAGE)),
+    };
+    let (mut imported, mut failed) = (0, 0);
+    for mapping in &mappings {
+        if conf.dry_run {
+            println!(
+                "DRY_RUN: NOT importing issue {} for {}.",
+                mapping.issue, mapping.submitter
+            );
+            continue;
+        }
+        let submitter = Address::new(None, mapping.submitter.clone());
+        match import_issue(conn, conf, mapping.issue, &submitter, mapping.anonymous) {
+            Ok((title, password)) => {
+                info!(
+                    "Imported issue {} for {}.",
+                    mapping.issue, mapping.submitter
+                );
+                // The submitter can't use an issue they haven't been told the password of.
+                if notify_submitters {
+                    if let Err(err) = notify(conn, conf, mapping, &title, password) {
+                        eprintln!(
+                            "Issue {}: imported, but could not notify {}: {}",
+                            mapping.issue, mapping.submitter, err
+                        );
+                        failed += 1;
+                        continue;
+                    }
+                }
+                imported += 1;
+            }
+            Err(err) => {
+                eprintln!("Issue {}: {}", mapping.issue, err);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} issue(s) imported, {} failed.", imported, failed);
+    if failed > 0 {
+        return Err(Error::new(format!(
+            "{} of {} issue(s) could not be imported or notified.",
+            failed,
+            mappings.len()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `contents` to a new temporary file and read it back as mappings.
+    fn read(contents: &str) -> Result<Vec<Mapping>> {
+        let path = std::env::temp_dir().join(format!("issue-bot-import-{}.csv", Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        let mappings = read_mappings(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        mappings
+    }
+
+    #[test]
+    fn csv_fields_quotes() {
+        assert_eq!(
+            csv_fields("1, jane@example.com ,"),
+            ["1", "jane@example.com", ""]
+        );
+        assert_eq!(
+            csv_fields(r#"2,"Doe, Jane <jane@example.com>",yes"#),
+            ["2", "Doe, Jane <jane@example.com>", "yes"]
+        );
+        assert_eq!(
+            csv_fields(r#"3,"Jane ""JD"" Doe <jane@example.com>""#),
+            ["3", r#"Jane "JD" Doe <jane@example.com>"#]
+        );
+        assert_eq!(csv_fields(r#""""#), [""]);
+        assert_eq!(csv_fields(r#"a""b"#), ["ab"]);
+    }
+
+    #[test]
+    fn read_csv_mappings() {
+        let mappings = read(
+            "Anonymous,Submitter,Issue\n\
+             yes,\"Doe, Jane <jane@example.com>\",12\n\
+             \n\
+             ,john@example.com,13\n",
+        )
+        .unwrap();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].issue, 12);
+        assert_eq!(mappings[0].submitter, "Doe, Jane <jane@example.com>");
+        assert!(mappings[0].anonymous);
+        assert_eq!(mappings[1].issue, 13);
+        assert_eq!(mappings[1].submitter, "john@example.com");
+        assert!(!mappings[1].anonymous);
+
+        let mappings = read("submitter,issue\njane@example.com,1\n").unwrap();
+        assert!(!mappings[0].anonymous);
+
+        let err = read("issue,address\n1,jane@example.com\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("expected a CSV header"), "{}", err);
+    }
+
+    #[test]
+    fn read_csv_errors() {
+        // Blank lines count, so that the line can be found in an editor.
+        let err = read("issue,submitter\n1,jane@example.com\n\nmany,john@example.com\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with(":4: invalid issue number `many`."), "{}", err);
+        let err = read("\nissue,submitter\n#2,jane@example.com\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with(":3: invalid issue number `#2`."), "{}", err);
+    }
+
+    #[test]
+    fn read_json_mappings() {
+        let mappings =
+            read(r#"[{"issue": 1, "submitter": "jane@example.com", "anonymous": true}]"#).unwrap();
+        assert_eq!(mappings[0].issue, 1);
+        assert!(mappings[0].anonymous);
+        assert!(read(r#"[{"issue": 1, "address": "jane@example.com"}]"#).is_err());
+    }
+}
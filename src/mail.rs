@@ -0,0 +1,257 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Building the final text of outgoing messages.
+//!
+//! melib's `Draft` only produces single-part text messages or `multipart/mixed` with attachments,
+//! and doesn't know about `format=flowed`, so the MIME structure of messages is put together here
+//! from the headers of the finalised draft.
+
+use super::*;
+
+/// What subscribers receive update notices and digests as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Plain text only.
+    Plain,
+    /// Plain text and HTML, with the Markdown of comments rendered.
+    Html,
+}
+
+impl Format {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::Plain => "plain",
+            Format::Html => "html",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [Format::Plain, Format::Html]
+            .into_iter()
+            .find(|f| f.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                Error::new(format!(
+                    "`{}` is not a format. Valid formats are: plain, html",
+                    s
+                ))
+            })
+    }
+}
+
+/// Add the RFC 2369 `List-*` headers of the notices of `issue` to `d`, for the subscriber with
+/// `password`. With `webhook.public_url` set, `List-Unsubscribe` also offers RFC 8058 one-click
+/// unsubscription over HTTP.
+pub fn add_list_headers(
+    d: &mut melib::Draft,
+    issue: &Issue,
+    password: Password,
+    conf: &Configuration,
+) {
+    let mut unsubscribe = format!(
+        "<mailto:{}+{}+unsubscribe@{}>",
+        conf.local_part, password, conf.domain
+    );
+    let public_url = conf.webhook.as_ref().and_then(|w| w.public_url.as_deref());
+    if let Some(public_url) = public_url {
+        unsubscribe.push_str(&format!(
+            ", <{}{}?password={}>",
+            public_url.trim_end_matches('/'),
+            webhook::UNSUBSCRIBE_PATH,
+            password
+        ));
+        d.headers_mut().insert(
+            HeaderName::new_unchecked("List-Unsubscribe-Post"),
+            "List-Unsubscribe=One-Click".to_string(),
+        );
+    }
+    d.headers_mut().insert(
+        HeaderName::new_unchecked("List-Id"),
+        format!("<{}.{}.{}>", issue.id, conf.local_part, conf.domain),
+    );
+    d.headers_mut()
+        .insert(HeaderName::new_unchecked("List-Unsubscribe"), unsubscribe);
+    d.headers_mut().insert(
+        HeaderName::new_unchecked("List-Archive"),
+        format!("<{}>", templates::issue_url(issue.id, conf)),
+    );
+    d.headers_mut().insert(
+        HeaderName::new_unchecked("List-Help"),
+        format!("<mailto:{}+help@{}>", conf.local_part, conf.domain),
+    );
+}
+
+/// MIME headers of the finalised draft, which are replaced by ours.
+const MIME_HEADERS: [&str; 3] = ["MIME-Version", "Content-Type", "Content-Transfer-Encoding"];
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=\"utf-8\"; format=flowed; delsp=no";
+
+/// Turn `d` into the text of a message. The draft's body is sent as `format=flowed` text, see
+/// [`flowed`]. With `html`, the message is `multipart/alternative` with `html` as the HTML part.
+pub fn finalise(mut d: melib::Draft, html: Option<&str>, conf: &Configuration) -> Result<String> {
+    let text = flowed(d.body(), conf.wrap_width);
+    d.set_body(String::new());
+    let message = d.finalise()?;
+    let headers = match message.split_once("\r\n\r\n") {
+        Some((headers, _)) => headers,
+        None => message.as_str(),
+    };
+    let mut ret = String::new();
+    let mut skip = false;
+    for line in headers.split("\r\n") {
+        // Continuation lines belong to the previous header.
+        if !line.starts_with([' ', '\t']) {
+            let name = line.split(':').next().unwrap_or_default().trim();
+            skip = MIME_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name));
+        }
+        if !skip {
+            ret.push_str(line);
+            ret.push_str("\r\n");
+        }
+    }
+    ret.push_str("MIME-Version: 1.0\r\n");
+    let Some(html) = html else {
+        ret.push_str(&part(TEXT_CONTENT_TYPE, &text)?);
+        return Ok(ret);
+    };
+    let boundary = format!("=_{}", Uuid::new_v4().simple());
+    ret.push_str(&format!(
+        "Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n",
+        boundary
+    ));
+    for (content_type, body) in [
+        (TEXT_CONTENT_TYPE, text.as_str()),
+        ("text/html; charset=\"utf-8\"", html),
+    ] {
+        ret.push_str(&format!("--{}\r\n{}", boundary, part(content_type, body)?));
+    }
+    ret.push_str(&format!("--{}--\r\n", boundary));
+    Ok(ret)
+}
+
+/// The headers and encoded body of a MIME part. ASCII text with lines short enough for SMTP is
+/// sent as is, anything else as base64.
+fn part(content_type: &str, body: &str) -> Result<String> {
+    let body = body.replace("\r\n", "\n").replace('\n', "\r\n");
+    let mut ret = format!("Content-Type: {}\r\n", content_type);
+    if body.is_ascii() && body.split("\r\n").all(|l| l.len() <= 998) {
+        ret.push_str("Content-Transfer-Encoding: 7bit\r\n\r\n");
+        ret.push_str(&body);
+        if !body.ends_with("\r\n") {
+            ret.push_str("\r\n");
+        }
+        return Ok(ret);
+    }
+    ret.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+    let encoded = base64::encode(body);
+    // RFC 2045 limits encoded lines to 76 characters.
+    for line in encoded.as_bytes().chunks(76) {
+        ret.push_str(std::str::from_utf8(line)?);
+        ret.push_str("\r\n");
+    }
+    Ok(ret)
+}
+
+/// Encode `text` as RFC 3676 `format=flowed` with `delsp=no`: lines longer than `width` are
+/// broken at spaces into soft line breaks, which end in a space, and flowed-aware clients join
+/// them again to fit their window. Words longer than `width`, eg. URLs, are never broken, and
+/// neither are indented lines, eg. code blocks, even when quoted.
+pub fn flowed(text: &str, width: usize) -> String {
+    let mut ret = String::new();
+    for line in text.lines() {
+        // The signature separator is the one hard line break that may end in a space.
+        let line = if line == "-- " { line } else { line.trim_end() };
+        let unquoted = line.trim_start_matches("> ").trim_start_matches('>');
+        let preformatted = unquoted.starts_with("    ") || unquoted.starts_with('\t');
+        if preformatted || line.chars().count() <= width {
+            push_stuffed(&mut ret, line);
+            continue;
+        }
+        let mut current = String::new();
+        let mut current_width = 0;
+        for word in line.split_inclusive(' ') {
+            let word_width = word.trim_end_matches(' ').chars().count();
+            if current_width > 0 && current_width + word_width > width {
+                push_stuffed(&mut ret, &current);
+                current.clear();
+                current_width = 0;
+            }
+            current.push_str(word);
+            current_width += word.chars().count();
+        }
+        push_stuffed(&mut ret, current.trim_end());
+    }
+    ret
+}
+
+/// Add `line` to `text`, with a space before it if it could be mistaken for a quote or for the
+/// `From ` line of an mbox.
+fn push_stuffed(text: &mut String, line: &str) {
+    if line.starts_with([' ', '>']) || line.starts_with("From ") {
+        text.push(' ');
+    }
+    text.push_str(line);
+    text.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flowed_wraps_at_spaces() {
+        assert_eq!(
+            flowed("one two three four five", 10),
+            "one two \nthree four \nfive\n"
+        );
+        // Short lines and trailing spaces of hard line breaks.
+        assert_eq!(flowed("short  \n\nlines", 10), "short\n\nlines\n");
+    }
+
+    #[test]
+    fn flowed_keeps_urls_and_code() {
+        let url = "https://git.meli.delivery/meli/meli/issues/1234#issuecomment-5678";
+        assert_eq!(
+            flowed(&format!("see {} for more", url), 20),
+            format!("see \n{} \nfor more\n", url)
+        );
+        let code = "    let x = some_function(with, many, arguments, that, go, on);";
+        assert_eq!(flowed(code, 20), format!(" {}\n", code));
+        let quoted = format!(">     {}", code.trim_start());
+        assert_eq!(flowed(&quoted, 20), format!(" {}\n", quoted));
+    }
+
+    #[test]
+    fn flowed_space_stuffing() {
+        assert_eq!(flowed("From me", 72), " From me\n");
+        assert_eq!(flowed("> quote", 72), " > quote\n");
+        assert_eq!(flowed(" indented", 72), "  indented\n");
+        assert_eq!(flowed("Fromage", 72), "Fromage\n");
+        // Lines that only start with `From ` after a soft line break are stuffed too.
+        assert_eq!(flowed("a From b", 3), "a \n From \nb\n");
+    }
+
+    #[test]
+    fn flowed_signature_separator() {
+        assert_eq!(flowed("text\n-- \nsignature", 72), "text\n-- \nsignature\n");
+        // Other lines never end in a space unless they are soft line breaks.
+        assert_eq!(flowed("--  ", 72), "--\n");
+    }
+}
@@ -23,6 +23,7 @@ extern crate error_chain;
 use log::{error, info, trace};
 use melib::email::headers::HeaderName;
 use melib::{Address, Envelope};
+use minijinja::context;
 use rusqlite::types::ToSql;
 use rusqlite::Connection;
 use simplelog::*;
@@ -32,14 +33,64 @@ use uuid::Uuid;
 
 mod error;
 pub use error::*;
+mod admin;
 mod api;
+mod backup;
 mod conf;
 use conf::*;
 mod cron;
+mod daemon;
+mod db;
+mod digest;
+use digest::Delivery;
+mod doctor;
+mod erase;
+mod events;
+mod import;
+mod mail;
+mod markdown;
+mod queue;
+use events::{Event, EventKind};
 mod templates;
+mod transport;
+mod webhook;
 
 type Password = Uuid;
-static PASSWORD_COMMANDS: &[&str] = &["reply", "unsubscribe", "subscribe", "close"];
+static PASSWORD_COMMANDS: &[&str] = &[
+    "reply",
+    "unsubscribe",
+    "subscribe",
+    "close",
+    "confirm",
+    "forget",
+];
+/// Commands that take an argument, eg. `+p+mute+labels` or `+p+delivery+daily`.
+static ARGUMENT_COMMANDS: &[&str] = &["mute", "unmute", "delivery", "format"];
+
+enum Command {
+    /// Read an e-mail from stdin and act on it.
+    Request,
+    /// Send notices for new activity in tracked issues.
+    Cron,
+    /// Validate the configuration and report what's wrong.
+    Doctor { test_mail: Option<Address> },
+    /// Listen for Gitea webhooks.
+    Serve,
+    /// Run `cron` periodically.
+    Daemon,
+    /// Inspect and manage the outgoing mail queue.
+    Queue(Vec<String>),
+    /// Delete issues closed for longer than the given age.
+    Prune { closed_older_than: chrono::Duration },
+    /// Inspect and manage tracked issues.
+    Admin(Vec<String>),
+    /// Track issues reported before the bot was deployed.
+    Import(Vec<String>),
+    /// Dump the database as JSON.
+    Export(Vec<String>),
+    /// Restore a JSON dump into a new database.
+    ImportDb(Vec<String>),
+}
 
 #[derive(Debug)]
 pub struct Issue {
@@ -51,38 +102,154 @@ pub struct Issue {
     subscribed: bool,
     title: String,
     last_update: String, // chrono::DateTime<chrono::FixedOffset>,
+    /// Kinds of change the submitter doesn't want to be notified about.
+    muted: Vec<EventKind>,
+    /// `None` until the issue's state has been fetched from Gitea.
+    closed: Option<bool>,
+}
+
+/// Queue `d` for delivery and try to deliver it right away. A failed delivery is not an error:
+/// the message stays in the queue and the next `cron` run retries it.
+pub fn send_mail(conn: &Connection, d: melib::email::Draft, conf: &Configuration) -> Result<()> {
+    if let Some(id) = queue_mail(conn, d, None, conf)? {
+        deliver_queued(conn, conf, &[id]);
+    }
+    Ok(())
 }
 
-pub fn send_mail(d: melib::email::Draft, conf: &Configuration) -> Result<()> {
-    use std::io::Write;
-    use std::process::Stdio;
-    let parts = conf.mailer.split_whitespace().collect::<Vec<&str>>();
-    let (cmd, args) = (parts[0], &parts[1..]);
+/// Deliver `d` right away without queuing it, for mail that must leave no trace of its recipient
+/// in the database, such as the confirmation of `+forget`. A failed delivery is logged and not
+/// retried.
+pub fn send_mail_unqueued(d: melib::email::Draft, conf: &Configuration) -> Result<()> {
     if conf.dry_run {
         eprintln!("DRY_RUN: NOT sending to the following email:\n{:?}\n", &d);
         return Ok(());
     }
-    let mut mailer = std::process::Command::new(cmd)
-        .args(args)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-    {
-        let stdin = mailer.stdin.as_mut().expect("failed to open stdin");
-        let draft = d.finalise()?;
-        stdin.write_all(draft.as_bytes())?;
-    }
-    let output = mailer.wait()?;
-    if !output.success() {
-        // TODO: commit to database queue
-        error!("mailer fail");
-        eprintln!("mailer fail");
-        return Err(Error::new(format!("Mailer failed. {:?}", output)));
+    if let Err(err) = transport::deliver(&mail::finalise(d, None, conf)?, conf) {
+        eprintln!("mailer fail: {}", err);
     }
     Ok(())
 }
 
+/// Only queue `d`, eg. inside a transaction that must not be held open while talking to the
+/// mailer. With `html` the message is sent as `multipart/alternative`, see [`mail::finalise`].
+/// Returns the queue id, or `None` in `dry_run`.
+pub fn queue_mail(
+    conn: &Connection,
+    d: melib::email::Draft,
+    html: Option<&str>,
+    conf: &Configuration,
+) -> Result<Option<i64>> {
+    if conf.dry_run {
+        eprintln!("DRY_RUN: NOT sending to the following email:\n{:?}\n", &d);
+        return Ok(None);
+    }
+    let recipient = d.headers().get("To").unwrap_or_default().to_string();
+    let subject = d.headers().get("Subject").unwrap_or_default().to_string();
+    Ok(Some(queue::enqueue(
+        conn,
+        &recipient,
+        &subject,
+        &mail::finalise(d, html, conf)?,
+    )?))
+}
+
+/// Try to deliver messages queued with [`queue_mail`] right away.
+pub fn deliver_queued(conn: &Connection, conf: &Configuration, ids: &[i64]) {
+    for id in ids {
+        if let Err(err) = queue::attempt(conn, conf, *id) {
+            eprintln!("mailer fail: {}", err);
+        }
+    }
+}
+
+/// Whether `address` is one of the bot's own, eg. `issues@` or `issues+anonymous@`.
+fn is_bot_address(address: &Address, conf: &Configuration) -> bool {
+    match address.get_email().rsplit_once('@') {
+        Some((local_part, domain)) => {
+            domain.eq_ignore_ascii_case(&conf.domain)
+                && (local_part == conf.local_part
+                    || local_part.starts_with(&format!("{}+", conf.local_part)))
+        }
+        None => false,
+    }
+}
+
+/// The language to answer a mail in: an explicit `+lang=xx` tag, which is removed from `tags`,
+/// or else the mail's `Content-Language` or `Accept-Language`, if we have templates for it.
+fn requested_language(envelope: &Envelope, tags: &mut Vec<String>) -> Option<String> {
+    if let Some(i) = tags.iter().position(|t| t.starts_with("lang=")) {
+        let tag = tags.remove(i);
+        return templates::supported_language(&tag["lang=".len()..]);
+    }
+    let headers = envelope.other_headers();
+    let mut ranges = vec![];
+    for header in ["Content-Language", "Accept-Language"] {
+        let Some(value) = headers.get(header) else {
+            continue;
+        };
+        // eg. `de-DE, en;q=0.5`
+        let mut values = value
+            .split(',')
+            .map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next().unwrap_or_default().trim().to_string();
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (tag, q)
+            })
+            .collect::<Vec<(String, f32)>>();
+        values.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges.extend(values.into_iter().map(|(tag, _)| tag));
+    }
+    ranges
+        .iter()
+        .find_map(|tag| templates::supported_language(tag))
+}
+
+/// Mail `address` a request to confirm following issue `issue_id`. The request always goes to
+/// `address` itself, never to a `Reply-To`, so nobody can subscribe someone else. It is written
+/// in the language `address` has chosen before, or else in `lang`.
+fn request_subscription(
+    conn: &Connection,
+    conf: &Configuration,
+    issue_id: i64,
+    address: Address,
+    invited_by: Option<&Address>,
+    lang: Option<&str>,
+) -> Result<()> {
+    let stored = db::language(conn, &address.to_string())?;
+    let lang = stored.as_deref().or(lang);
+    let (title, password) = api::subscribe_request(conn, issue_id, &address)?;
+    let mut request = melib::Draft::default();
+    request.headers_mut().insert(
+        HeaderName::new_unchecked("From"),
+        format!(
+            "{local_part}@{domain}",
+            local_part = &conf.local_part,
+            domain = &conf.domain
+        ),
+    );
+    request
+        .headers_mut()
+        .insert(HeaderName::new_unchecked("To"), address.to_string());
+    request.headers_mut().insert(
+        HeaderName::new_unchecked("Subject"),
+        templates::subject(
+            "subscription_request",
+            context! { title => &title },
+            lang,
+            conf,
+        )?,
+    );
+    request.set_body(templates::subscription_request(
+        &title, password, issue_id, invited_by, lang, conf,
+    )?);
+    send_mail(conn, request, conf)
+}
+
 fn run_request(conn: Connection, conf: Configuration) -> Result<()> {
     let mut new_message_raw = vec![];
     stdin().lock().read_to_end(&mut new_message_raw)?;
@@ -103,7 +270,20 @@ fn run_request(conn: Connection, conf: Configuration) -> Result<()> {
         ),
     );
 
-    let tags: Vec<String> = envelope.to()[0].get_tags('+');
+    let mut tags: Vec<String> = envelope.to()[0].get_tags('+');
+    // Remember the language the sender asked for, and use the one they asked for before if the
+    // mail doesn't say.
+    let sender = envelope.from()[0].to_string();
+    let lang = match requested_language(&envelope, &mut tags) {
+        Some(lang) => {
+            if !conf.dry_run {
+                db::set_language(&conn, &sender, &lang)?;
+            }
+            Some(lang)
+        }
+        None => db::language(&conn, &sender)?,
+    };
+    let lang = lang.as_deref();
     match tags.as_slice() {
         s if s.is_empty() || s == ["anonymous"] => {
             /* Assign new issue */
@@ -121,34 +301,53 @@ fn run_request(conn: Connection, conf: Configuration) -> Result<()> {
                     domain = &conf.domain
                 ),
             );
-            match api::new_issue(&conn, subject.clone(), body, anonymous, from, &conf) {
+            match api::new_issue(&conn, subject.clone(), body, anonymous, from.clone(), &conf) {
                 Ok((password, issue_id)) => {
                     info!("Issue {} successfully created.", &subject);
+                    for cc in envelope
+                        .to()
+                        .iter()
+                        .chain(envelope.cc().iter())
+                        .filter(|a| !is_bot_address(a, &conf) && a.get_email() != from.get_email())
+                    {
+                        if let Err(err) = request_subscription(
+                            &conn,
+                            &conf,
+                            issue_id,
+                            cc.clone(),
+                            Some(&from),
+                            lang,
+                        ) {
+                            error!("Could not invite {} to issue {}: {}", cc, issue_id, err);
+                        }
+                    }
                     reply.headers_mut().insert(
                         HeaderName::new_unchecked("Subject"),
-                        format!(
-                            "[{tag}] Issue `{}` successfully created",
-                            &subject,
-                            tag = &conf.tag
-                        ),
+                        templates::subject(
+                            "new_issue_success",
+                            context! { title => &subject },
+                            lang,
+                            &conf,
+                        )?,
                     );
                     reply.set_body(templates::new_issue_success(
-                        subject, password, issue_id, &conf,
-                    ));
-                    send_mail(reply, &conf)?;
+                        subject, password, issue_id, lang, &conf,
+                    )?);
+                    send_mail(&conn, reply, &conf)?;
                 }
                 Err(err) => {
                     error!("Issue {} could not be created {}.", &subject, &err);
                     reply.headers_mut().insert(
                         HeaderName::new_unchecked("Subject"),
-                        format!(
-                            "[{tag}] Issue `{}` could not be created",
-                            &subject,
-                            tag = &conf.tag
-                        ),
+                        templates::subject(
+                            "new_issue_failure",
+                            context! { title => &subject },
+                            lang,
+                            &conf,
+                        )?,
                     );
-                    reply.set_body(templates::new_issue_failure(err, &conf));
-                    send_mail(reply, &conf)?;
+                    reply.set_body(templates::new_issue_failure(err, lang)?);
+                    send_mail(&conn, reply, &conf)?;
                 }
             }
         }
@@ -171,20 +370,22 @@ fn run_request(conn: Connection, conf: Configuration) -> Result<()> {
                             info!("Reply successfully created.");
                             reply.headers_mut().insert(
                                 HeaderName::new_unchecked("Subject"),
-                                format!(
-                                    "[{tag}] Your reply on issue `{}` has been posted",
-                                    &title,
-                                    tag = &conf.tag,
-                                ),
+                                templates::subject(
+                                    "new_reply_success",
+                                    context! { title => &title },
+                                    lang,
+                                    &conf,
+                                )?,
                             );
                             reply.set_body(templates::new_reply_success(
                                 title,
                                 p,
                                 issue_id,
                                 is_subscribed,
+                                lang,
                                 &conf,
-                            ));
-                            send_mail(reply, &conf)?;
+                            )?);
+                            send_mail(&conn, reply, &conf)?;
                         }
                         Err(err) => {
                             error!(
@@ -192,14 +393,12 @@ fn run_request(conn: Connection, conf: Configuration) -> Result<()> {
                                 &envelope.subject(),
                                 &err
                             );
-                            reply.headers_mut().insert(HeaderName::new_unchecked("Subject"),
-                                    format!(
-                                        "[{tag}] Your reply could not be created",
-                                        tag = &conf.tag,
-                                    ),
-                                );
-                            reply.set_body(templates::new_reply_failure(err, &conf));
-                            send_mail(reply, &conf)?;
+                            reply.headers_mut().insert(
+                                HeaderName::new_unchecked("Subject"),
+                                templates::subject("new_reply_failure", context! {}, lang, &conf)?,
+                            );
+                            reply.set_body(templates::new_reply_failure(err, lang)?);
+                            send_mail(&conn, reply, &conf)?;
                         }
                     }
                 }
@@ -207,117 +406,400 @@ fn run_request(conn: Connection, conf: Configuration) -> Result<()> {
                     Ok((title, issue_id, _)) => {
                         reply.headers_mut().insert(
                             HeaderName::new_unchecked("Subject"),
-                            format!(
-                                "[{tag}] issue `{}` has been closed",
-                                &title,
-                                tag = &conf.tag
-                            ),
+                            templates::subject(
+                                "close_success",
+                                context! { title => &title },
+                                lang,
+                                &conf,
+                            )?,
+                        );
+                        reply.set_body(templates::close_success(title, issue_id, lang, &conf)?);
+                        send_mail(&conn, reply, &conf)?;
+                    }
+                    Err(e) => {
+                        reply.headers_mut().insert(
+                            HeaderName::new_unchecked("Subject"),
+                            templates::subject("close_failure", context! {}, lang, &conf)?,
+                        );
+                        reply.set_body(templates::close_failure(e, lang)?);
+                        send_mail(&conn, reply, &conf)?;
+                    }
+                },
+                "forget" => match erase::forget(&conn, p, &conf) {
+                    Ok(erased) => {
+                        reply.headers_mut().insert(
+                            HeaderName::new_unchecked("Subject"),
+                            templates::subject("forget_success", context! {}, lang, &conf)?,
                         );
-                        reply.set_body(templates::close_success(title, issue_id, &conf));
-                        send_mail(reply, &conf)?;
+                        reply.set_body(templates::forget_success(&erased, lang, &conf)?);
+                        // Queuing it would store the address again.
+                        send_mail_unqueued(reply, &conf)?;
                     }
                     Err(e) => {
+                        error!("forget error: {}", e.to_string());
                         reply.headers_mut().insert(
                             HeaderName::new_unchecked("Subject"),
-                            format!("[{tag}] issue could not be closed", tag = &conf.tag,),
+                            templates::subject("forget_failure", context! {}, lang, &conf)?,
                         );
-                        reply.set_body(templates::close_failure(e, &conf));
-                        send_mail(reply, &conf)?;
+                        reply.set_body(templates::forget_failure(e, lang)?);
+                        send_mail(&conn, reply, &conf)?;
                     }
                 },
                 "unsubscribe" => match api::change_subscription(&conn, p, false) {
                     Ok((title, issue_id, _)) => {
                         reply.headers_mut().insert(
                             HeaderName::new_unchecked("Subject"),
-                            format!(
-                                "[{tag}] subscription removal to `{}` successful",
-                                &title,
-                                tag = &conf.tag
-                            ),
+                            templates::subject(
+                                "change_subscription_success",
+                                context! { title => &title, subscribed => false },
+                                lang,
+                                &conf,
+                            )?,
                         );
                         reply.set_body(templates::change_subscription_success(
-                            title, p, issue_id, false, &conf,
-                        ));
-                        send_mail(reply, &conf)?;
+                            title, p, issue_id, false, lang, &conf,
+                        )?);
+                        send_mail(&conn, reply, &conf)?;
                     }
                     Err(e) => {
                         error!("unsubscribe error: {}", e.to_string());
                         reply.headers_mut().insert(
                             HeaderName::new_unchecked("Subject"),
-                            format!("[{tag}] could not unsubscribe", tag = &conf.tag,),
+                            templates::subject(
+                                "change_subscription_failure",
+                                context! { subscribed => false },
+                                lang,
+                                &conf,
+                            )?,
                         );
-                        reply.set_body(templates::change_subscription_failure(false, &conf));
-                        send_mail(reply, &conf)?;
+                        reply.set_body(templates::change_subscription_failure(false, lang)?);
+                        send_mail(&conn, reply, &conf)?;
                     }
                 },
                 "subscribe" => match api::change_subscription(&conn, p, true) {
                     Ok((title, issue_id, _)) => {
                         reply.headers_mut().insert(
                             HeaderName::new_unchecked("Subject"),
-                            format!(
-                                "[{tag}] subscription to `{}` successful",
-                                &title,
-                                tag = &conf.tag
-                            ),
+                            templates::subject(
+                                "change_subscription_success",
+                                context! { title => &title, subscribed => true },
+                                lang,
+                                &conf,
+                            )?,
                         );
                         reply.set_body(templates::change_subscription_success(
-                            title, p, issue_id, true, &conf,
-                        ));
-                        send_mail(reply, &conf)?;
+                            title, p, issue_id, true, lang, &conf,
+                        )?);
+                        send_mail(&conn, reply, &conf)?;
                     }
                     Err(e) => {
                         error!("subscribe error: {}", e.to_string());
                         reply.headers_mut().insert(
                             HeaderName::new_unchecked("Subject"),
-                            format!("[{tag}] could not subscribe", tag = &conf.tag,),
+                            templates::subject(
+                                "change_subscription_failure",
+                                context! { subscribed => true },
+                                lang,
+                                &conf,
+                            )?,
                         );
-                        reply.set_body(templates::change_subscription_failure(true, &conf));
-                        send_mail(reply, &conf)?;
+                        reply.set_body(templates::change_subscription_failure(true, lang)?);
+                        send_mail(&conn, reply, &conf)?;
                     }
                 },
 
+                "confirm" => match api::confirm_subscription(&conn, p) {
+                    Ok((title, issue_id)) => {
+                        reply.headers_mut().insert(
+                            HeaderName::new_unchecked("Subject"),
+                            templates::subject(
+                                "confirm_subscription_success",
+                                context! { title => &title },
+                                lang,
+                                &conf,
+                            )?,
+                        );
+                        reply.set_body(templates::confirm_subscription_success(
+                            title, p, issue_id, lang, &conf,
+                        )?);
+                        send_mail(&conn, reply, &conf)?;
+                    }
+                    Err(e) => {
+                        error!("confirm error: {}", e.to_string());
+                        reply.headers_mut().insert(
+                            HeaderName::new_unchecked("Subject"),
+                            templates::subject(
+                                "subscription_request_failure",
+                                context! {},
+                                lang,
+                                &conf,
+                            )?,
+                        );
+                        reply.set_body(templates::subscription_request_failure(e, lang)?);
+                        send_mail(&conn, reply, &conf)?;
+                    }
+                },
                 other => {
                     reply.headers_mut().insert(
                         HeaderName::new_unchecked("Subject"),
-                        format!("[{tag}] invalid action: `{}`", &other, tag = &conf.tag),
+                        templates::subject(
+                            "invalid_request",
+                            context! { action => other },
+                            lang,
+                            &conf,
+                        )?,
                     );
-                    reply.set_body(templates::invalid_request(&conf));
-                    send_mail(reply, &conf)?;
+                    reply.set_body(templates::invalid_request(lang)?);
+                    send_mail(&conn, reply, &conf)?;
+                }
+            }
+        }
+        [n, cmd] if cmd == "subscribe" && n.parse::<i64>().is_ok() => {
+            let issue_id = n.parse::<i64>()?;
+            let from = envelope.from()[0].clone();
+            info!(
+                "Got subscription request for issue {} from {}.",
+                issue_id, &from
+            );
+            if let Err(e) = request_subscription(&conn, &conf, issue_id, from, None, lang) {
+                error!("subscription request error: {}", e.to_string());
+                reply.headers_mut().insert(
+                    HeaderName::new_unchecked("Subject"),
+                    templates::subject("subscription_request_failure", context! {}, lang, &conf)?,
+                );
+                reply.set_body(templates::subscription_request_failure(e, lang)?);
+                send_mail(&conn, reply, &conf)?;
+            }
+        }
+        [p, cmd, arg]
+            if Password::parse_str(p).is_ok() && ARGUMENT_COMMANDS.contains(&cmd.as_str()) =>
+        {
+            trace!("Got command {} from {}", cmd.as_str(), &envelope.from()[0]);
+            let p = Password::parse_str(p)?;
+            match cmd.as_str() {
+                "delivery" => match arg.parse::<Delivery>().and_then(|delivery| {
+                    Ok((digest::change_delivery(&conn, p, delivery)?, delivery))
+                }) {
+                    Ok(((title, issue_id, _), delivery)) => {
+                        reply.headers_mut().insert(
+                            HeaderName::new_unchecked("Subject"),
+                            templates::subject(
+                                "change_delivery_success",
+                                context! { delivery => delivery.as_str() },
+                                lang,
+                                &conf,
+                            )?,
+                        );
+                        reply.set_body(templates::change_delivery_success(
+                            title, p, issue_id, delivery, lang, &conf,
+                        )?);
+                        send_mail(&conn, reply, &conf)?;
+                    }
+                    Err(e) => {
+                        error!("delivery error: {}", e.to_string());
+                        reply.headers_mut().insert(
+                            HeaderName::new_unchecked("Subject"),
+                            templates::subject(
+                                "change_delivery_failure",
+                                context! {},
+                                lang,
+                                &conf,
+                            )?,
+                        );
+                        reply.set_body(templates::change_delivery_failure(e, lang)?);
+                        send_mail(&conn, reply, &conf)?;
+                    }
+                },
+                "format" => match arg.parse::<mail::Format>().and_then(|format| {
+                    Ok((
+                        db::change_format(&conn, p, format == mail::Format::Html)?,
+                        format,
+                    ))
+                }) {
+                    Ok(((title, issue_id), format)) => {
+                        reply.headers_mut().insert(
+                            HeaderName::new_unchecked("Subject"),
+                            templates::subject(
+                                "change_format_success",
+                                context! { format => format.as_str() },
+                                lang,
+                                &conf,
+                            )?,
+                        );
+                        reply.set_body(templates::change_format_success(
+                            title,
+                            p,
+                            issue_id,
+                            format == mail::Format::Html,
+                            lang,
+                            &conf,
+                        )?);
+                        send_mail(&conn, reply, &conf)?;
+                    }
+                    Err(e) => {
+                        error!("format error: {}", e.to_string());
+                        reply.headers_mut().insert(
+                            HeaderName::new_unchecked("Subject"),
+                            templates::subject("change_format_failure", context! {}, lang, &conf)?,
+                        );
+                        reply.set_body(templates::change_format_failure(e, lang)?);
+                        send_mail(&conn, reply, &conf)?;
+                    }
+                },
+                _ => {
+                    let mute = cmd == "mute";
+                    match arg
+                        .parse::<EventKind>()
+                        .and_then(|kind| Ok((api::change_muted(&conn, p, kind, mute)?, kind)))
+                    {
+                        Ok(((title, issue_id), kind)) => {
+                            reply.headers_mut().insert(
+                                HeaderName::new_unchecked("Subject"),
+                                templates::subject(
+                                    "change_muted_success",
+                                    context! { title => &title, kind => kind.as_str(), mute },
+                                    lang,
+                                    &conf,
+                                )?,
+                            );
+                            reply.set_body(templates::change_muted_success(
+                                title, p, issue_id, kind, mute, lang, &conf,
+                            )?);
+                            send_mail(&conn, reply, &conf)?;
+                        }
+                        Err(e) => {
+                            error!("{} error: {}", cmd, e.to_string());
+                            reply.headers_mut().insert(
+                                HeaderName::new_unchecked("Subject"),
+                                templates::subject(
+                                    "change_muted_failure",
+                                    context! { mute },
+                                    lang,
+                                    &conf,
+                                )?,
+                            );
+                            reply.set_body(templates::change_muted_failure(e, lang)?);
+                            send_mail(&conn, reply, &conf)?;
+                        }
+                    }
                 }
             }
         }
         other => {
             reply.headers_mut().insert(
                 HeaderName::new_unchecked("Subject"),
-                format!("[{tag}] invalid request", tag = &conf.tag),
+                templates::subject(
+                    "invalid_request",
+                    context! { action => None::<&str> },
+                    lang,
+                    &conf,
+                )?,
             );
-            reply.set_body(templates::invalid_request(&conf));
-            send_mail(reply, &conf)?;
+            reply.set_body(templates::invalid_request(lang)?);
+            send_mail(&conn, reply, &conf)?;
             error!("invalid request: {:?}", other);
         }
     }
     Ok(())
 }
 
-fn run_app() -> Result<()> {
-    let conf_path =
-        std::env::var("ISSUE_BOT_CONFIG").unwrap_or_else(|_| "./config.toml".to_string());
-    let mut file = std::fs::File::open(&conf_path)?;
-    let args = std::env::args().skip(1).collect::<Vec<String>>();
-    let perform_cron: bool;
-    if args.len() > 1 {
-        return Err(Error::new("Too many arguments."));
-    } else if args == ["cron"] {
-        perform_cron = true;
-    } else if args.is_empty() {
-        perform_cron = false;
-    } else {
-        return Err(Error::new("Usage: issue_bot [cron]"));
+/// Parse an age such as `90d`, `12w` or `36h`.
+fn parse_age(age: &str) -> Result<chrono::Duration> {
+    let invalid = || {
+        Error::new(format!(
+            "Invalid age `{}`, expected eg. `90d`, `12w` or `36h`.",
+            age
+        ))
+    };
+    let (count, unit) = [("h", 3600), ("d", 24 * 3600), ("w", 7 * 24 * 3600)]
+        .iter()
+        .find_map(|(suffix, unit)| Some((age.strip_suffix(suffix)?, *unit)))
+        .ok_or_else(invalid)?;
+    let count = count.parse::<u64>().map_err(|_| invalid())?;
+    if count == 0 {
+        return Err(invalid());
+    }
+    count
+        .checked_mul(unit)
+        .and_then(|secs| chrono::Duration::from_std(std::time::Duration::from_secs(secs)).ok())
+        .ok_or_else(|| Error::new(format!("Age `{}` is too large.", age)))
+}
+
+fn prune(
+    conn: &Connection,
+    conf: &Configuration,
+    closed_older_than: chrono::Duration,
+) -> Result<()> {
+    let cutoff = chrono::Utc::now()
+        .checked_sub_signed(closed_older_than)
+        .ok_or_else(|| Error::new("Age is too large."))?;
+    if conf.dry_run {
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM issue WHERE state = 'closed' AND closed_at < ?",
+            [cutoff.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)],
+            |row| row.get(0),
+        )?;
+        println!("DRY_RUN: NOT deleting {} issue(s).", count);
+        return Ok(());
     }
+    let ids = db::prune(conn, cutoff)?;
+    for id in &ids {
+        info!("Pruned issue {}.", id);
+    }
+    println!("{} issue(s) deleted.", ids.len());
+    Ok(())
+}
 
+fn load_configuration(path: &str) -> Result<Configuration> {
+    let mut file = std::fs::File::open(path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
     let conf: Configuration = toml::from_str(&contents)?;
+    conf.validate()?;
+    Ok(conf)
+}
+
+fn run_app() -> Result<()> {
+    let conf_path =
+        std::env::var("ISSUE_BOT_CONFIG").unwrap_or_else(|_| "./config.toml".to_string());
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let command = match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .as_slice()
+    {
+        [] => Command::Request,
+        ["cron"] => Command::Cron,
+        ["serve"] => Command::Serve,
+        ["daemon"] => Command::Daemon,
+        ["queue", rest @ ..] => Command::Queue(rest.iter().map(|s| s.to_string()).collect()),
+        ["prune", "--closed-older-than", age] => Command::Prune {
+            closed_older_than: parse_age(age)?,
+        },
+        [
+            "list" | "show" | "subscribe" | "unsubscribe" | "reset-token" | "forget" | "untrack"
+            | "resend-confirmation",
+            ..,
+        ] => Command::Admin(args.clone()),
+        ["import", rest @ ..] => Command::Import(rest.iter().map(|s| s.to_string()).collect()),
+        ["export", rest @ ..] => Command::Export(rest.iter().map(|s| s.to_string()).collect()),
+        ["import-db", rest @ ..] => {
+            Command::ImportDb(rest.iter().map(|s| s.to_string()).collect())
+        }
+        ["doctor"] => Command::Doctor { test_mail: None },
+        ["doctor", "--send-test-mail", address] => Command::Doctor {
+            test_mail: Some(Address::new(None, address.to_string())),
+        },
+        _ => {
+            return Err(Error::new(
+                "Usage: issue_bot [cron | serve | daemon | queue [list | retry [<id>] | purge [<id>]] | prune --closed-older-than <age> | list | show | subscribe | unsubscribe | reset-token | forget | resend-confirmation | import | export [<file>] | import-db <file> | doctor [--send-test-mail <address>]]",
+            ))
+        }
+    };
+
+    let conf = load_configuration(&conf_path)?;
+    templates::load(&conf)?;
     CombinedLogger::init(vec![
         TermLogger::new(LevelFilter::Error, Config::default(), TerminalMode::Mixed),
         WriteLogger::new(
@@ -342,34 +824,56 @@ fn run_app() -> Result<()> {
      *
      */
     let db_path = std::env::var("ISSUE_BOT_DB").unwrap_or_else(|_| "./sqlite3.db".to_string());
-    let conn = Connection::open(&db_path)?;
-
-    conn.execute_batch(
-        r##"CREATE TABLE IF NOT EXISTS issue (
-                  id              INTEGER PRIMARY KEY,
-                  submitter       TEXT NOT NULL,
-                  password        BLOB,
-                  time_created    TEXT NOT NULL,
-                  anonymous       BOOLEAN,
-                  subscribed      BOOLEAN,
-                  title           TEXT NOT NULL,
-                  last_update     TEXT
-                  );
-
-        UPDATE issue SET last_update = replace(last_update, '"', '');
-        "##,
-    )?;
+    let conn = db::open(&db_path)?;
+    db::init(&conn)?;
 
-    if perform_cron {
-        info!("Performing cron duties.");
-        if let Err(err) = cron::check(conn, conf) {
-            error!("Encountered an error: {}", &err);
-            return Err(err);
-        }
-        return Ok(());
-    }
-
-    if let Err(err) = run_request(conn, conf) {
+    let result = match command {
+        Command::Request => run_request(conn, conf),
+        Command::Cron => match db::lock(&format!("{}.lock", db_path))? {
+            Some(_lock) => {
+                info!("Performing cron duties.");
+                cron::check(&conn, &conf)
+            }
+            None => {
+                info!("Another cron run or the daemon is running, exiting.");
+                Ok(())
+            }
+        },
+        Command::Doctor { test_mail } => doctor::run(&conn, &conf, test_mail),
+        Command::Serve => webhook::serve(conn, conf),
+        Command::Daemon => match db::lock(&format!("{}.lock", db_path))? {
+            Some(_lock) => daemon::run(conn, conf, &conf_path),
+            None => Err(Error::new(
+                "Another cron run or daemon is using the database, exiting.",
+            )),
+        },
+        Command::Queue(args) => queue::run(
+            &conn,
+            &conf,
+            &args.iter().map(String::as_str).collect::<Vec<&str>>(),
+        ),
+        Command::Prune { closed_older_than } => prune(&conn, &conf, closed_older_than),
+        Command::Admin(args) => admin::run(
+            &conn,
+            &conf,
+            &args.iter().map(String::as_str).collect::<Vec<&str>>(),
+        ),
+        Command::Import(args) => import::run(
+            &conn,
+            &conf,
+            &args.iter().map(String::as_str).collect::<Vec<&str>>(),
+        ),
+        Command::Export(args) => backup::run_export(
+            &conn,
+            &args.iter().map(String::as_str).collect::<Vec<&str>>(),
+        ),
+        Command::ImportDb(args) => backup::run_restore(
+            &conn,
+            &conf,
+            &args.iter().map(String::as_str).collect::<Vec<&str>>(),
+        ),
+    };
+    if let Err(err) = result {
         error!("Encountered an error: {}", &err);
         return Err(err);
     }
@@ -382,3 +886,35 @@ fn main() {
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_age_units() {
+        assert_eq!(parse_age("36h").unwrap(), chrono::Duration::hours(36));
+        assert_eq!(parse_age("90d").unwrap(), chrono::Duration::days(90));
+        assert_eq!(parse_age("12w").unwrap(), chrono::Duration::weeks(12));
+    }
+
+    #[test]
+    fn parse_age_invalid() {
+        for age in [
+            "", "d", "90", "90m", "90D", "9 0d", "-1d", "0d", "0w", "1.5d", "90д", "дd",
+        ] {
+            assert!(parse_age(age).is_err(), "{:?} was accepted", age);
+        }
+    }
+
+    #[test]
+    fn parse_age_overflow() {
+        for age in [
+            "99999999999999w",
+            "18446744073709551615h",
+            "99999999999999999999d",
+        ] {
+            assert!(parse_age(age).is_err(), "{:?} was accepted", age);
+        }
+    }
+}
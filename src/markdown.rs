@@ -0,0 +1,414 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Rendering the Markdown of Gitea comments for mail: to sanitised HTML for the HTML part and to
+//! readable plain text for the plain text part.
+//!
+//! In the plain text, links become numbered references listed after the comment, eg.
+//! `see the docs [1]` and `[1] https://example.com/docs`, code blocks are indented by four
+//! spaces and quotes keep their `> `. In both, relative URLs such as those of attachments
+//! (`/attachments/{uuid}`) are made absolute against `conf.base_url`, and links to a fragment
+//! such as `#issuecomment-1` against the URL of the comment's issue.
+
+use super::*;
+use pulldown_cmark::{html, CowStr, Event, LinkType, Options, Parser, Tag, TagEnd};
+
+fn options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES
+}
+
+/// Make `url` absolute: `/path` is relative to the Gitea instance, `path` to the repository and
+/// `#fragment` to issue `issue_id`, as they are on Gitea's page of the issue.
+fn absolute_url(url: &str, issue_id: i64, conf: &Configuration) -> String {
+    let base_url = conf.base_url.trim_end_matches('/');
+    let has_scheme = url
+        .split_once(':')
+        .map(|(scheme, _)| {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        })
+        .unwrap_or(false);
+    if has_scheme || url.starts_with("//") {
+        url.to_string()
+    } else if url.starts_with('#') {
+        format!("{}{}", templates::issue_url(issue_id, conf), url)
+    } else if url.starts_with('/') {
+        format!("{}{}", base_url, url)
+    } else {
+        format!("{}/{}/{}", base_url, conf.repo, url)
+    }
+}
+
+/// Parse `markdown`, posted on issue `issue_id`, with the URLs of links and images made absolute.
+fn parse<'m>(
+    markdown: &'m str,
+    issue_id: i64,
+    conf: &'m Configuration,
+) -> impl Iterator<Item = Event<'m>> {
+    Parser::new_ext(markdown, options()).map(move |event| match event {
+        // The URL of `<user@example.com>` is the bare address.
+        Event::Start(Tag::Link {
+            link_type: LinkType::Email,
+            ..
+        }) => event,
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: CowStr::from(absolute_url(&dest_url, issue_id, conf)),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: CowStr::from(absolute_url(&dest_url, issue_id, conf)),
+            title,
+            id,
+        }),
+        other => other,
+    })
+}
+
+/// Render `markdown`, posted on issue `issue_id`, to HTML that is safe to put in a mail:
+/// scripts, styles, event handlers and the like are removed.
+pub fn to_html(markdown: &str, issue_id: i64, conf: &Configuration) -> String {
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parse(markdown, issue_id, conf));
+    ammonia::clean(&unsafe_html)
+}
+
+/// Render `markdown`, posted on issue `issue_id`, as plain text.
+pub fn to_text(markdown: &str, issue_id: i64, conf: &Configuration) -> String {
+    let mut text = Text::default();
+    for event in parse(markdown, issue_id, conf) {
+        text.event(event);
+    }
+    text.finish()
+}
+
+#[derive(Default)]
+struct Text {
+    out: String,
+    /// What every line of the enclosing blocks starts with, eg. `> ` in quotes.
+    prefixes: Vec<String>,
+    /// The marker of a list item, eg. `- ` or `2. `, until its first line is written.
+    marker: Option<String>,
+    /// The next number of each enclosing list, `None` for bullet lists.
+    lists: Vec<Option<u64>>,
+    /// Links being written: their URL and where their text starts in `out`.
+    open_links: Vec<(String, usize)>,
+    /// URLs referenced so far, numbered from 1.
+    links: Vec<String>,
+    line_start: bool,
+    /// A blank line is due before the next block.
+    blank: bool,
+    /// The next table cell is the first of its row.
+    first_cell: bool,
+}
+
+impl Text {
+    fn prefix(&self) -> String {
+        self.prefixes.concat()
+    }
+
+    fn push(&mut self, text: &str) {
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.newline();
+            }
+            if line.is_empty() {
+                continue;
+            }
+            if self.line_start || self.out.is_empty() {
+                let mut prefix = self.prefix();
+                if let Some(marker) = self.marker.take() {
+                    // The item's indentation is the last prefix and as wide as its marker.
+                    prefix.truncate(prefix.len() - marker.len());
+                    prefix.push_str(&marker);
+                }
+                self.out.push_str(&prefix);
+                self.line_start = false;
+            }
+            self.out.push_str(line);
+        }
+    }
+
+    fn newline(&mut self) {
+        if self.line_start {
+            // Keep the `>` of quotes on blank lines.
+            self.out.push_str(self.prefix().trim_end());
+        }
+        self.out.push('\n');
+        self.line_start = true;
+    }
+
+    fn start_block(&mut self) {
+        if !self.out.is_empty() {
+            if !self.line_start {
+                self.newline();
+            }
+            if self.blank {
+                self.newline();
+            }
+        }
+        self.blank = false;
+    }
+
+    fn end_block(&mut self) {
+        if !self.line_start && !self.out.is_empty() {
+            self.newline();
+        }
+        self.blank = true;
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) | Event::Html(text) | Event::InlineHtml(text) => self.push(&text),
+            Event::Code(code) => self.push(&format!("`{}`", code)),
+            Event::InlineMath(math) => self.push(&format!("${}$", math)),
+            Event::DisplayMath(math) => self.push(&format!("$${}$$", math)),
+            Event::FootnoteReference(label) => self.push(&format!("[^{}]", label)),
+            Event::SoftBreak | Event::HardBreak => self.newline(),
+            Event::Rule => {
+                self.start_block();
+                self.push("-----");
+                self.end_block();
+            }
+            Event::TaskListMarker(checked) => self.push(if checked { "[x] " } else { "[ ] " }),
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph | Tag::HtmlBlock | Tag::Table(_) => self.start_block(),
+            Tag::Heading { level, .. } => {
+                self.start_block();
+                self.push(&format!("{} ", "#".repeat(level as usize)));
+            }
+            Tag::BlockQuote(_) => {
+                self.start_block();
+                self.prefixes.push("> ".to_string());
+            }
+            Tag::CodeBlock(_) => {
+                self.start_block();
+                self.prefixes.push("    ".to_string());
+            }
+            Tag::List(start) => {
+                self.start_block();
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                if !self.line_start && !self.out.is_empty() {
+                    self.newline();
+                }
+                if self.blank {
+                    self.newline();
+                    self.blank = false;
+                }
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "- ".to_string(),
+                };
+                self.prefixes.push(" ".repeat(marker.len()));
+                self.marker = Some(marker);
+            }
+            Tag::FootnoteDefinition(label) => {
+                self.start_block();
+                self.push(&format!("[^{}]: ", label));
+            }
+            Tag::TableHead | Tag::TableRow => self.first_cell = true,
+            Tag::TableCell => {
+                if !self.first_cell {
+                    self.push(" | ");
+                }
+                self.first_cell = false;
+            }
+            Tag::Emphasis => self.push("_"),
+            Tag::Strong => self.push("*"),
+            Tag::Strikethrough => self.push("~"),
+            Tag::Link { dest_url, .. } | Tag::Image { dest_url, .. } => {
+                self.open_links.push((dest_url.to_string(), self.out.len()));
+            }
+            Tag::Superscript
+            | Tag::Subscript
+            | Tag::DefinitionList
+            | Tag::DefinitionListTitle
+            | Tag::DefinitionListDefinition
+            | Tag::MetadataBlock(_) => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph
+            | TagEnd::Heading(_)
+            | TagEnd::HtmlBlock
+            | TagEnd::Table
+            | TagEnd::FootnoteDefinition => self.end_block(),
+            TagEnd::BlockQuote(_) | TagEnd::CodeBlock => {
+                self.prefixes.pop();
+                self.end_block();
+            }
+            TagEnd::List(_) => {
+                self.lists.pop();
+                self.end_block();
+            }
+            TagEnd::Item => {
+                self.prefixes.pop();
+                self.marker = None;
+                if !self.line_start {
+                    self.newline();
+                }
+            }
+            TagEnd::TableHead | TagEnd::TableRow => self.newline(),
+            TagEnd::Emphasis => self.push("_"),
+            TagEnd::Strong => self.push("*"),
+            TagEnd::Strikethrough => self.push("~"),
+            TagEnd::Link | TagEnd::Image => self.end_link(tag == TagEnd::Image),
+            _ => {}
+        }
+    }
+
+    fn end_link(&mut self, image: bool) {
+        let Some((url, start)) = self.open_links.pop() else {
+            return;
+        };
+        let text = self.out[start..].trim();
+        // Autolinks and links whose text is their URL need no reference.
+        if text == url || url.strip_prefix("mailto:") == Some(text) {
+            return;
+        }
+        if image && text.is_empty() {
+            self.push("image");
+        }
+        let number = match self.links.iter().position(|l| *l == url) {
+            Some(i) => i + 1,
+            None => {
+                self.links.push(url);
+                self.links.len()
+            }
+        };
+        self.push(&format!(" [{}]", number));
+    }
+
+    fn finish(mut self) -> String {
+        let mut ret = self.out.trim_end().to_string();
+        if !self.links.is_empty() {
+            ret.push('\n');
+            for (i, url) in self.links.drain(..).enumerate() {
+                ret.push_str(&format!("\n[{}] {}", i + 1, url));
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(markdown: &str) -> String {
+        to_text(markdown, 12, &conf::example())
+    }
+
+    #[test]
+    fn link_footnotes() {
+        assert_eq!(
+            text("See [the docs](https://example.com/docs) and [them](https://example.com/docs)."),
+            "See the docs [1] and them [1].\n\n[1] https://example.com/docs"
+        );
+        assert_eq!(
+            text("[a](https://a.example) [b](https://b.example)"),
+            "a [1] b [2]\n\n[1] https://a.example\n[2] https://b.example"
+        );
+        // Links whose text is their URL are left as they are.
+        assert_eq!(
+            text("<https://example.com> <user@example.com>"),
+            "https://example.com user@example.com"
+        );
+    }
+
+    #[test]
+    fn absolute_attachment_urls() {
+        assert_eq!(
+            text("![](/attachments/1234)"),
+            "image [1]\n\n[1] https://git.meli.delivery/attachments/1234"
+        );
+        assert_eq!(
+            text("[log](src/main.rs)"),
+            "log [1]\n\n[1] https://git.meli.delivery/meli/meli/src/main.rs"
+        );
+        assert_eq!(
+            text("[log](#issuecomment-1)"),
+            "log [1]\n\n[1] https://git.meli.delivery/meli/meli/issues/12#issuecomment-1"
+        );
+        assert_eq!(
+            text("see [below](#logs), [there](https://example.com/#logs)"),
+            "see below [1], there [2]\n\n\
+             [1] https://git.meli.delivery/meli/meli/issues/12#logs\n\
+             [2] https://example.com/#logs"
+        );
+        let html = to_html(
+            "![x](/attachments/1234) [log](#issuecomment-1)",
+            12,
+            &conf::example(),
+        );
+        assert!(html.contains("src=\"https://git.meli.delivery/attachments/1234\""));
+        assert!(
+            html.contains("href=\"https://git.meli.delivery/meli/meli/issues/12#issuecomment-1\"")
+        );
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            text("> first\n>\n> second\n\nreply"),
+            "> first\n>\n> second\n\nreply"
+        );
+        assert_eq!(text("> > nested\n> outer"), "> > nested\n> > outer");
+    }
+
+    #[test]
+    fn code_indent() {
+        assert_eq!(
+            text("Run:\n\n```\ncargo build\n  --release\n```\n\ndone"),
+            "Run:\n\n    cargo build\n      --release\n\ndone"
+        );
+        assert_eq!(
+            text("- item\n\n  ```\n  code\n  ```"),
+            "- item\n\n      code"
+        );
+        assert_eq!(text("inline `code`"), "inline `code`");
+    }
+}
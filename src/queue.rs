@@ -0,0 +1,351 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Outgoing mail is written to the `queue` table before it is handed to the mailer, so nothing
+//! is lost when the mailer fails. Failed messages are retried with exponential backoff by every
+//! `cron` run until they are delivered or run out of attempts.
+
+use super::*;
+
+#[derive(Debug)]
+pub struct QueuedMessage {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub message: String,
+    pub time_created: String,
+    pub attempts: u32,
+    pub next_attempt: String,
+    pub last_error: Option<String>,
+    /// `pending` or `failed`, once `max_attempts` is reached.
+    pub status: String,
+}
+
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<QueuedMessage> {
+    Ok(QueuedMessage {
+        id: row.get(0)?,
+        recipient: row.get(1)?,
+        subject: row.get(2)?,
+        message: row.get(3)?,
+        time_created: row.get(4)?,
+        attempts: row.get(5)?,
+        next_attempt: row.get(6)?,
+        last_error: row.get(7)?,
+        status: row.get(8)?,
+    })
+}
+
+/// How many seconds a message claimed by [`attempt`] is left alone by other processes.
+const DELIVERY_LEASE: i64 = 600;
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+pub fn enqueue(conn: &Connection, recipient: &str, subject: &str, message: &str) -> Result<i64> {
+    let now = now();
+    conn.execute(
+        "INSERT INTO queue (recipient, subject, message, time_created, attempts, next_attempt, status)
+            VALUES (?1, ?2, ?3, ?4, 0, ?4, 'pending')",
+        rusqlite::params![recipient, subject, message, &now],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Claim message `id` if it is due by moving its next attempt past the time a delivery may take,
+/// so that a concurrent `drain` skips it. If we crash, it is retried after the lease expires.
+fn claim(conn: &Connection, id: i64) -> Result<bool> {
+    let claimed = conn.execute(
+        "UPDATE queue SET next_attempt = ?1
+            WHERE id = ?2 AND status = 'pending' AND next_attempt <= ?3",
+        rusqlite::params![
+            (chrono::Utc::now() + chrono::Duration::seconds(DELIVERY_LEASE))
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            id,
+            now()
+        ],
+    )?;
+    Ok(claimed > 0)
+}
+
+/// Try to deliver message `id` once. On success it is removed from the queue, otherwise the
+/// error is recorded and the next attempt is scheduled. Returns `false` without delivering if
+/// the message isn't due or another process is already delivering it.
+pub fn attempt(conn: &Connection, conf: &Configuration, id: i64) -> Result<bool> {
+    if !claim(conn, id)? {
+        return Ok(false);
+    }
+    let message = conn.query_row("SELECT * FROM queue WHERE id = ?", [id], message_from_row)?;
+    match transport::deliver(&message.message, conf) {
+        Ok(()) => {
+            conn.execute("DELETE FROM queue WHERE id = ?", [id])?;
+            Ok(true)
+        }
+        Err(err) => {
+            let attempts = message.attempts + 1;
+            let backoff = chrono::Duration::seconds(
+                (conf.queue.retry_backoff as i64).saturating_mul(1 << attempts.min(16)) / 2,
+            );
+            let status = if attempts >= conf.queue.max_attempts {
+                "failed"
+            } else {
+                "pending"
+            };
+            error!(
+                "Could not deliver message {} to {} (attempt {}): {}",
+                id, &message.recipient, attempts, err
+            );
+            conn.execute(
+                "UPDATE queue SET attempts = ?1, next_attempt = ?2, last_error = ?3, status = ?4
+                    WHERE id = ?5",
+                rusqlite::params![
+                    attempts,
+                    (chrono::Utc::now() + backoff)
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    err.to_string(),
+                    status,
+                    id
+                ],
+            )?;
+            Err(err)
+        }
+    }
+}
+
+/// Attempt every pending message that is due. Returns how many were delivered and how many
+/// failed again.
+pub fn drain(conn: &Connection, conf: &Configuration) -> Result<(usize, usize)> {
+    let mut stmt = conn.prepare(
+        "SELECT id FROM queue WHERE status = 'pending' AND next_attempt <= ? ORDER BY id",
+    )?;
+    let ids = stmt
+        .query_map([now()], |row| row.get(0))?
+        .collect::<std::result::Result<Vec<i64>, _>>()?;
+    let (mut sent, mut failed) = (0, 0);
+    for id in ids {
+        match attempt(conn, conf, id) {
+            Ok(true) => sent += 1,
+            Ok(false) => {}
+            Err(_) => failed += 1,
+        }
+    }
+    Ok((sent, failed))
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<QueuedMessage>> {
+    let mut stmt = conn.prepare("SELECT * FROM queue ORDER BY id")?;
+    let messages = stmt
+        .query_map([], message_from_row)?
+        .collect::<std::result::Result<Vec<QueuedMessage>, _>>()?;
+    Ok(messages)
+}
+
+/// Make message `id`, or every failed message, due again and try to deliver it.
+pub fn retry(conn: &Connection, conf: &Configuration, id: Option<i64>) -> Result<(usize, usize)> {
+    let changed = match id {
+        Some(id) => conn.execute(
+            "UPDATE queue SET status = 'pending', attempts = 0, next_attempt = ?1 WHERE id = ?2",
+            rusqlite::params![now(), id],
+        )?,
+        None => conn.execute(
+            "UPDATE queue SET status = 'pending', attempts = 0, next_attempt = ?1
+                WHERE status = 'failed'",
+            [now()],
+        )?,
+    };
+    if changed == 0 && id.is_some() {
+        return Err(Error::new("No such message in the queue."));
+    }
+    drain(conn, conf)
+}
+
+/// Delete message `id`, or every failed message. Returns how many were deleted.
+pub fn purge(conn: &Connection, id: Option<i64>) -> Result<usize> {
+    Ok(match id {
+        Some(id) => conn.execute("DELETE FROM queue WHERE id = ?", [id])?,
+        None => conn.execute("DELETE FROM queue WHERE status = 'failed'", [])?,
+    })
+}
+
+/// `issue-bot queue [list | retry [<id>] | purge [<id>]]`
+pub fn run(conn: &Connection, conf: &Configuration, args: &[&str]) -> Result<()> {
+    match args {
+        [] | ["list"] => {
+            let messages = list(conn)?;
+            if messages.is_empty() {
+                println!("The queue is empty.");
+            }
+            for m in messages {
+                println!(
+                    "{id}\t{status}\t{attempts} attempt(s)\tqueued {created}\tnext attempt {next}\n\tTo: {to}\n\tSubject: {subject}{error}",
+                    id = m.id,
+                    status = m.status,
+                    attempts = m.attempts,
+                    created = m.time_created,
+                    next = m.next_attempt,
+                    to = m.recipient,
+                    subject = m.subject,
+                    error = m
+                        .last_error
+                        .map(|e| format!("\n\tLast error: {}", e))
+                        .unwrap_or_default()
+                );
+            }
+        }
+        ["retry"] | ["retry", _] => {
+            let id = args.get(1).map(|id| id.parse::<i64>()).transpose()?;
+            let (sent, failed) = retry(conn, conf, id)?;
+            println!("{} message(s) delivered, {} failed.", sent, failed);
+        }
+        ["purge"] | ["purge", _] => {
+            let id = args.get(1).map(|id| id.parse::<i64>()).transpose()?;
+            println!("{} message(s) deleted.", purge(conn, id)?);
+        }
+        _ => {
+            return Err(Error::new(
+                "Usage: issue_bot queue [list | retry [<id>] | purge [<id>]]",
+            ))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration whose mailer always fails.
+    fn failing() -> Configuration {
+        let mut conf = conf::example();
+        conf.mailer = Some("false".to_string());
+        conf.queue.max_attempts = 3;
+        conf.queue.retry_backoff = 60;
+        conf
+    }
+
+    fn message(conn: &Connection, id: i64) -> QueuedMessage {
+        conn.query_row("SELECT * FROM queue WHERE id = ?", [id], message_from_row)
+            .unwrap()
+    }
+
+    /// Seconds from now until the next attempt of message `id`.
+    fn wait(conn: &Connection, id: i64) -> i64 {
+        let next = chrono::DateTime::parse_from_rfc3339(&message(conn, id).next_attempt).unwrap();
+        next.signed_duration_since(chrono::Utc::now()).num_seconds()
+    }
+
+    /// Make message `id` due again.
+    fn make_due(conn: &Connection, id: i64) {
+        conn.execute(
+            "UPDATE queue SET next_attempt = ? WHERE id = ?",
+            rusqlite::params![now(), id],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn enqueue_pending() {
+        let conn = db::open_test();
+        let id = enqueue(&conn, "jane@example.com", "Re: Issue 1", "Hello").unwrap();
+        let m = message(&conn, id);
+        assert_eq!(
+            (m.recipient.as_str(), m.subject.as_str(), m.message.as_str()),
+            ("jane@example.com", "Re: Issue 1", "Hello")
+        );
+        assert_eq!(
+            (m.attempts, m.status.as_str(), m.last_error),
+            (0, "pending", None)
+        );
+        assert!(wait(&conn, id) <= 0);
+    }
+
+    #[test]
+    fn lease() {
+        let conn = db::open_test();
+        let id = enqueue(&conn, "jane@example.com", "Re: Issue 1", "Hello").unwrap();
+        assert!(claim(&conn, id).unwrap());
+        assert!((DELIVERY_LEASE - 5..=DELIVERY_LEASE).contains(&wait(&conn, id)));
+        // Another process doesn't deliver it while the lease lasts.
+        assert!(!claim(&conn, id).unwrap());
+        assert!(!attempt(&conn, &failing(), id).unwrap());
+        assert_eq!(drain(&conn, &failing()).unwrap(), (0, 0));
+        assert_eq!(message(&conn, id).attempts, 0);
+    }
+
+    #[test]
+    fn backoff_until_failed() {
+        let conn = db::open_test();
+        let conf = failing();
+        let id = enqueue(&conn, "jane@example.com", "Re: Issue 1", "Hello").unwrap();
+        for attempts in 1..conf.queue.max_attempts {
+            assert!(attempt(&conn, &conf, id).is_err());
+            let m = message(&conn, id);
+            assert_eq!((m.attempts, m.status.as_str()), (attempts, "pending"));
+            assert!(m.last_error.is_some());
+            // 60s, then 120s: doubled after every attempt.
+            let backoff = 60 << (attempts - 1);
+            assert!((backoff - 5..=backoff).contains(&wait(&conn, id)));
+            // Not due until the backoff has passed.
+            assert_eq!(drain(&conn, &conf).unwrap(), (0, 0));
+            make_due(&conn, id);
+        }
+        assert_eq!(drain(&conn, &conf).unwrap(), (0, 1));
+        let m = message(&conn, id);
+        assert_eq!((m.attempts, m.status.as_str()), (3, "failed"));
+        // Failed messages are left alone.
+        make_due(&conn, id);
+        assert_eq!(drain(&conn, &conf).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn retry_and_purge() {
+        let conn = db::open_test();
+        let mut conf = failing();
+        conf.queue.max_attempts = 1;
+        let first = enqueue(&conn, "jane@example.com", "Re: Issue 1", "Hello").unwrap();
+        enqueue(&conn, "john@example.com", "Re: Issue 2", "Hello").unwrap();
+        enqueue(&conn, "joe@example.com", "Re: Issue 3", "Hello").unwrap();
+        assert_eq!(drain(&conn, &conf).unwrap(), (0, 3));
+        assert!(list(&conn).unwrap().iter().all(|m| m.status == "failed"));
+
+        // Retrying one starts its attempts over, and it fails again.
+        assert_eq!(retry(&conn, &conf, Some(first)).unwrap(), (0, 1));
+        assert_eq!(message(&conn, first).attempts, 1);
+        assert!(retry(&conn, &conf, Some(first + 10)).is_err());
+        // Retrying every failed message with a working mailer delivers them.
+        conf.mailer = Some("true".to_string());
+        assert_eq!(retry(&conn, &conf, None).unwrap(), (3, 0));
+        assert!(list(&conn).unwrap().is_empty());
+
+        let first = enqueue(&conn, "jane@example.com", "Re: Issue 1", "Hello").unwrap();
+        let pending = enqueue(&conn, "john@example.com", "Re: Issue 2", "Hello").unwrap();
+        conn.execute("UPDATE queue SET status = 'failed' WHERE id = ?", [first])
+            .unwrap();
+        // Only failed messages are purged, unless one is named.
+        assert_eq!(purge(&conn, None).unwrap(), 1);
+        assert_eq!(
+            list(&conn)
+                .unwrap()
+                .iter()
+                .map(|m| m.id)
+                .collect::<Vec<i64>>(),
+            [pending]
+        );
+        assert_eq!(purge(&conn, Some(pending)).unwrap(), 1);
+        assert_eq!(purge(&conn, Some(pending)).unwrap(), 0);
+        assert!(list(&conn).unwrap().is_empty());
+    }
+}
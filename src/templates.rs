@@ -14,47 +14,592 @@
  * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+//! Mail texts, rendered with [minijinja](https://docs.rs/minijinja) from the templates in the
+//! `templates/` directory of the source tree. Any of them can be replaced by a file of the same
+//! name in `template_dir`, and translated by a file in a subdirectory named after the language,
+//! eg. `de/new_issue_success.txt`. Templates that haven't been translated fall back to the
+//! language of the configuration, `conf.language`.
+//!
+//! Besides their own variables, every template can use `bot_name`, `local_part`, `domain` and the
+//! `address(action, password)` function, eg. `address("reply", password)` for
+//! `{local_part}+{password}+reply@{domain}` or `address("help")` for `{local_part}+help@{domain}`.
+//!
+//! Update notices and digests also have `.html` templates for their HTML part. Values are
+//! HTML-escaped in those.
+
 use super::*;
+use minijinja::{context, Environment, UndefinedBehavior, Value};
+use std::sync::RwLock;
 
 static BASE_ISSUE_URL: &str = "{base_url}/{repo}/issues";
 
-pub fn new_issue_failure(e: Error, conf: &Configuration) -> String {
-    format!("Hello,
+/// Every template, read from the `$dir` subdirectory of `templates/`.
+macro_rules! catalog {
+    ($dir:literal) => {
+        &[
+            (
+                "new_issue_failure",
+                include_str!(concat!("../templates/", $dir, "new_issue_failure.txt")),
+            ),
+            (
+                "new_issue_success",
+                include_str!(concat!("../templates/", $dir, "new_issue_success.txt")),
+            ),
+            (
+                "new_reply_failure",
+                include_str!(concat!("../templates/", $dir, "new_reply_failure.txt")),
+            ),
+            (
+                "new_reply_success",
+                include_str!(concat!("../templates/", $dir, "new_reply_success.txt")),
+            ),
+            (
+                "close_success",
+                include_str!(concat!("../templates/", $dir, "close_success.txt")),
+            ),
+            (
+                "close_failure",
+                include_str!(concat!("../templates/", $dir, "close_failure.txt")),
+            ),
+            (
+                "invalid_request",
+                include_str!(concat!("../templates/", $dir, "invalid_request.txt")),
+            ),
+            (
+                "change_subscription_success",
+                include_str!(concat!(
+                    "../templates/",
+                    $dir,
+                    "change_subscription_success.txt"
+                )),
+            ),
+            (
+                "change_subscription_failure",
+                include_str!(concat!(
+                    "../templates/",
+                    $dir,
+                    "change_subscription_failure.txt"
+                )),
+            ),
+            (
+                "event",
+                include_str!(concat!("../templates/", $dir, "event.txt")),
+            ),
+            (
+                "event_section",
+                include_str!(concat!("../templates/", $dir, "event_section.txt")),
+            ),
+            (
+                "update_sections.html",
+                include_str!(concat!("../templates/", $dir, "update_sections.html")),
+            ),
+            (
+                "comment",
+                include_str!(concat!("../templates/", $dir, "comment.txt")),
+            ),
+            (
+                "reply_update",
+                include_str!(concat!("../templates/", $dir, "reply_update.txt")),
+            ),
+            (
+                "reply_update.html",
+                include_str!(concat!("../templates/", $dir, "reply_update.html")),
+            ),
+            (
+                "digest",
+                include_str!(concat!("../templates/", $dir, "digest.txt")),
+            ),
+            (
+                "digest.html",
+                include_str!(concat!("../templates/", $dir, "digest.html")),
+            ),
+            (
+                "change_delivery_success",
+                include_str!(concat!(
+                    "../templates/",
+                    $dir,
+                    "change_delivery_success.txt"
+                )),
+            ),
+            (
+                "change_delivery_failure",
+                include_str!(concat!(
+                    "../templates/",
+                    $dir,
+                    "change_delivery_failure.txt"
+                )),
+            ),
+            (
+                "change_format_success",
+                include_str!(concat!("../templates/", $dir, "change_format_success.txt")),
+            ),
+            (
+                "change_format_failure",
+                include_str!(concat!("../templates/", $dir, "change_format_failure.txt")),
+            ),
+            (
+                "change_muted_success",
+                include_str!(concat!("../templates/", $dir, "change_muted_success.txt")),
+            ),
+            (
+                "change_muted_failure",
+                include_str!(concat!("../templates/", $dir, "change_muted_failure.txt")),
+            ),
+            (
+                "test_mail",
+                include_str!(concat!("../templates/", $dir, "test_mail.txt")),
+            ),
+            (
+                "subscription_request",
+                include_str!(concat!("../templates/", $dir, "subscription_request.txt")),
+            ),
+            (
+                "subscription_request_failure",
+                include_str!(concat!(
+                    "../templates/",
+                    $dir,
+                    "subscription_request_failure.txt"
+                )),
+            ),
+            (
+                "confirm_subscription_success",
+                include_str!(concat!(
+                    "../templates/",
+                    $dir,
+                    "confirm_subscription_success.txt"
+                )),
+            ),
+            (
+                "import_success",
+                include_str!(concat!("../templates/", $dir, "import_success.txt")),
+            ),
+            (
+                "forget_success",
+                include_str!(concat!("../templates/", $dir, "forget_success.txt")),
+            ),
+            (
+                "forget_failure",
+                include_str!(concat!("../templates/", $dir, "forget_failure.txt")),
+            ),
+            (
+                "subject",
+                include_str!(concat!("../templates/", $dir, "subject.txt")),
+            ),
+        ]
+    };
+}
+
+/// The built-in templates, by name.
+static TEMPLATES: &[(&str, &str)] = catalog!("");
 
-Unfortunately we were not able to create your issue. The reason was: `{}`. Please contact the repository's owners for assistance.
+/// The built-in translations of [`TEMPLATES`], by language.
+static CATALOGS: &[(&str, &[(&str, &str)])] = &[
+    ("de", catalog!("de/")),
+    ("el", catalog!("el/")),
+    ("es", catalog!("es/")),
+];
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>",  e, local_part = &conf.local_part, domain = &conf.domain, bot_name = &conf.bot_name)
+/// The names the `subject` template has a subject for.
+static SUBJECTS: &[&str] = &[
+    "new_issue_success",
+    "new_issue_failure",
+    "new_reply_success",
+    "new_reply_failure",
+    "close_success",
+    "close_failure",
+    "forget_success",
+    "forget_failure",
+    "change_subscription_success",
+    "change_subscription_failure",
+    "subscription_request",
+    "confirm_subscription_success",
+    "subscription_request_failure",
+    "change_delivery_success",
+    "change_delivery_failure",
+    "change_format_success",
+    "change_format_failure",
+    "change_muted_success",
+    "change_muted_failure",
+    "reply_update",
+    "digest",
+    "test_mail",
+    "import_success",
+    "invalid_request",
+];
+
+struct Loaded {
+    env: Environment<'static>,
+    /// `conf.language` followed by every other language with templates, built-in or in
+    /// `template_dir`.
+    languages: Vec<String>,
 }
 
-pub fn new_issue_success(
-    title: String,
-    password: Password,
-    issue_id: i64,
-    conf: &Configuration,
-) -> String {
-    format!("Hello,
+static LOADED: RwLock<Option<Loaded>> = RwLock::new(None);
 
-You have successfully submitted an issue titled \"{title}\". Your issue can be found at
+/// The file name of template `name`: HTML templates are named with their extension, so that
+/// minijinja escapes their values.
+fn file_name(name: &str) -> String {
+    if name.ends_with(".html") {
+        name.to_string()
+    } else {
+        format!("{}.txt", name)
+    }
+}
 
-{url}/{issue_id}
+/// The built-in template the file at `path` replaces.
+fn template_name(path: &std::path::Path) -> Result<&'static str> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| TEMPLATES.iter().find(|(t, _)| file_name(t) == n))
+        .map(|(t, _)| *t)
+        .ok_or_else(|| {
+            Error::new(format!(
+                "Unknown template file {}, expected one of {}.",
+                path.display(),
+                TEMPLATES
+                    .iter()
+                    .map(|(t, _)| file_name(t))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ))
+        })
+}
 
-You will receive replies from other users. To unsubscribe from the conversation, send an email to {local_part}+{password}+unsubscribe@{domain}.
+/// Load the templates, preferring files in `conf.template_dir` over the built-in ones. Every
+/// template is rendered once with example values, so that syntax errors, typos in variable names
+/// and unknown files are reported now instead of when a mail has to be sent. On error the
+/// previously loaded templates are kept.
+pub fn load(conf: &Configuration) -> Result<()> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.add_global("bot_name", conf.bot_name.clone());
+    env.add_global("local_part", conf.local_part.clone());
+    env.add_global("domain", conf.domain.clone());
+    let (local_part, domain) = (conf.local_part.clone(), conf.domain.clone());
+    env.add_function(
+        "address",
+        move |action: String, password: Option<String>| match password {
+            Some(password) => format!("{}+{}+{}@{}", local_part, password, action, domain),
+            None => format!("{}+{}@{}", local_part, action, domain),
+        },
+    );
+    // Templates are registered as `{language}/{name}`. Those at the top of `template_dir` are in
+    // `conf.language`.
+    let default = conf.language.to_lowercase();
+    let mut overrides: Vec<(String, String)> = vec![];
+    let mut languages = vec![default.clone(), "en".to_string()];
+    languages.extend(CATALOGS.iter().map(|(language, _)| language.to_string()));
+    if let Some(ref dir) = conf.template_dir {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                let language = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or_default()
+                    .to_lowercase();
+                for entry in std::fs::read_dir(&path)? {
+                    let path = entry?.path();
+                    overrides.push((
+                        format!("{}/{}", language, template_name(&path)?),
+                        std::fs::read_to_string(&path)?,
+                    ));
+                }
+                languages.push(language);
+            } else {
+                overrides.push((
+                    format!("{}/{}", default, template_name(&path)?),
+                    std::fs::read_to_string(&path)?,
+                ));
+            }
+        }
+    }
+    let mut seen = vec![];
+    languages.retain(|l| {
+        let new = !seen.contains(l);
+        seen.push(l.clone());
+        new
+    });
+    for (language, templates) in std::iter::once(&("en", TEMPLATES)).chain(CATALOGS) {
+        for (name, source) in templates.iter() {
+            let name = format!("{}/{}", language, name);
+            let source = match overrides.iter().position(|(n, _)| *n == name) {
+                Some(i) => overrides.swap_remove(i).1,
+                None => source.to_string(),
+            };
+            env.add_template_owned(name.clone(), source)
+                .map_err(|err| Error::new(format!("Template `{}`: {:#}", name, err)))?;
+        }
+    }
+    for (name, source) in overrides {
+        env.add_template_owned(name.clone(), source)
+            .map_err(|err| Error::new(format!("Template `{}`: {:#}", name, err)))?;
+    }
+    let previous = LOADED.write().unwrap().replace(Loaded {
+        env,
+        languages: languages.clone(),
+    });
+    for language in &languages {
+        if let Err(err) = render_examples(Some(language), conf) {
+            *LOADED.write().unwrap() = previous;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
 
-To reply to other users or post new comments, send your text to {local_part}+{password}+reply@{domain}.
+/// Match a language tag, eg. from a `Content-Language` header, against the languages we have
+/// templates for. `de-AT` matches `de-at` or else `de`.
+pub fn supported_language(tag: &str) -> Option<String> {
+    let tag = tag.trim().to_lowercase();
+    let loaded = LOADED.read().unwrap();
+    let languages = &loaded.as_ref()?.languages;
+    let found = [tag.as_str(), tag.split('-').next().unwrap_or_default()]
+        .into_iter()
+        .find(|t| languages.iter().any(|l| l == t))
+        .map(str::to_string);
+    found
+}
 
-To close the issue, send an email to {local_part}+{password}+close@{domain}.
+/// Render every template in `lang` with made up values.
+fn render_examples(lang: Option<&str>, conf: &Configuration) -> Result<()> {
+    let password = Uuid::nil();
+    let issue = Issue {
+        id: 1,
+        submitter: Address::new(None, "submitter@example.com".to_string()),
+        password,
+        time_created: String::new(),
+        anonymous: false,
+        subscribed: true,
+        title: "Example".to_string(),
+        last_update: String::new(),
+        muted: vec![],
+        closed: Some(false),
+    };
+    let subscriber = db::Subscriber {
+        address: issue.submitter.clone(),
+        password,
+        muted: vec![],
+    };
+    let user = || "user".to_string();
+    let events = [
+        Event::State {
+            user: user(),
+            closed: true,
+        },
+        Event::State {
+            user: user(),
+            closed: false,
+        },
+        Event::Label {
+            user: user(),
+            label: "bug".to_string(),
+            added: true,
+        },
+        Event::Label {
+            user: user(),
+            label: "bug".to_string(),
+            added: false,
+        },
+        Event::Assignee {
+            user: user(),
+            assignee: user(),
+            removed: false,
+        },
+        Event::Assignee {
+            user: user(),
+            assignee: user(),
+            removed: true,
+        },
+        Event::Milestone {
+            user: user(),
+            milestone: Some("1.0".to_string()),
+        },
+        Event::Milestone {
+            user: user(),
+            milestone: None,
+        },
+    ];
+    let events = events.iter().collect::<Vec<&Event>>();
+    let comments = [
+        Comment {
+            issue_id: issue.id,
+            author: Some(user()),
+            body: "A *comment*.".to_string(),
+        },
+        Comment {
+            issue_id: issue.id,
+            author: None,
+            body: "A comment by the bot.".to_string(),
+        },
+    ];
+    let error = || Error::new("example error");
+    for subscribed in [true, false] {
+        new_reply_success(issue.title.clone(), password, 1, subscribed, lang, conf)?;
+        change_subscription_success(issue.title.clone(), password, 1, subscribed, lang, conf)?;
+        change_subscription_failure(subscribed, lang)?;
+        change_muted_success(
+            issue.title.clone(),
+            password,
+            1,
+            EventKind::Labels,
+            subscribed,
+            lang,
+            conf,
+        )?;
+    }
+    for html in [true, false] {
+        change_format_success(issue.title.clone(), password, 1, html, lang, conf)?;
+        reply_update(&issue, &subscriber, lang, conf, &events, &comments, html)?;
+    }
+    for delivery in Delivery::ALL {
+        change_delivery_success(issue.title.clone(), password, 1, delivery, lang, conf)?;
+    }
+    for anonymous in [true, false] {
+        import_success(&issue.title, password, 1, anonymous, lang, conf)?;
+    }
+    for invited_by in [None, Some(&issue.submitter)] {
+        subscription_request(&issue.title, password, 1, invited_by, lang, conf)?;
+    }
+    new_issue_failure(error(), lang)?;
+    new_issue_success(issue.title.clone(), password, 1, lang, conf)?;
+    new_reply_failure(error(), lang)?;
+    close_success(issue.title.clone(), 1, lang, conf)?;
+    close_failure(error(), lang)?;
+    for (issues, failed) in [
+        (vec![], vec![]),
+        (vec![(1, issue.title.clone())], vec![issue_url(1, conf)]),
+    ] {
+        forget_success(
+            &erase::Erased {
+                issues,
+                subscriptions: 1,
+                posts: 2,
+                failed,
+            },
+            lang,
+            conf,
+        )?;
+    }
+    forget_failure(error(), lang)?;
+    invalid_request(lang)?;
+    reply_update(&issue, &subscriber, lang, conf, &[], &comments, true)?;
+    reply_update_html(&issue, &subscriber, lang, conf, &events, &comments)?;
+    reply_update_html(&issue, &subscriber, lang, conf, &[], &comments)?;
+    digest(
+        lang,
+        conf,
+        &[(
+            1,
+            issue.title.clone(),
+            password,
+            vec![update_sections(&events, &comments, lang, conf)?],
+        )],
+    )?;
+    digest_html(
+        lang,
+        conf,
+        &[(
+            1,
+            issue.title.clone(),
+            password,
+            vec![update_sections_html(&events, &comments, lang, conf)?],
+        )],
+    )?;
+    change_format_failure(error(), lang)?;
+    change_delivery_failure(error(), lang)?;
+    change_muted_failure(error(), lang)?;
+    test_mail(lang)?;
+    subscription_request_failure(error(), lang)?;
+    confirm_subscription_success(issue.title.clone(), password, 1, lang, conf)?;
+    for name in SUBJECTS {
+        for (flag, action) in [(true, Some("reply")), (false, None)] {
+            subject(
+                name,
+                context! {
+                    title => &issue.title,
+                    subscribed => flag,
+                    mute => flag,
+                    replies => flag,
+                    action,
+                    delivery => "daily",
+                    format => "html",
+                    kind => "labels",
+                    count => 2,
+                },
+                lang,
+                conf,
+            )?;
+        }
+    }
+    Ok(())
+}
 
-Please keep this email in order to be able to keep in touch with your issue.
+/// Render template `name` in `lang`, or else in `conf.language`, or else in English.
+fn render(name: &str, lang: Option<&str>, ctx: Value) -> Result<String> {
+    let loaded = LOADED.read().unwrap();
+    let loaded = loaded
+        .as_ref()
+        .ok_or_else(|| Error::new("Templates have not been loaded."))?;
+    let default = loaded.languages[0].as_str();
+    let template = [lang.unwrap_or(default), default, "en"]
+        .into_iter()
+        .find_map(|l| loaded.env.get_template(&format!("{}/{}", l, name)).ok())
+        .ok_or_else(|| Error::new(format!("Template `{}` not found.", name)))?;
+    template
+        .render(ctx)
+        .map_err(|err| Error::new(format!("Template `{}`: {:#}", template.name(), err)))
+}
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>", title = title, password = password, issue_id = issue_id, url = BASE_ISSUE_URL.replace("{base_url}", &conf.base_url).replace("{repo}", &conf.repo), local_part = &conf.local_part, domain = &conf.domain, bot_name = &conf.bot_name)
+/// The subject of a mail: `[tag]` followed by the `name` case of the `subject` template, one of
+/// [`SUBJECTS`].
+pub fn subject(name: &str, ctx: Value, lang: Option<&str>, conf: &Configuration) -> Result<String> {
+    let subject = render("subject", lang, context! { name, ..ctx })?;
+    Ok(format!("[{}] {}", conf.tag, subject.trim()))
 }
 
-pub fn new_reply_failure(e: Error, conf: &Configuration) -> String {
-    format!("Hello,
+pub fn issue_url(issue_id: i64, conf: &Configuration) -> String {
+    format!(
+        "{}/{}",
+        BASE_ISSUE_URL
+            .replace("{base_url}", &conf.base_url)
+            .replace("{repo}", &conf.repo),
+        issue_id
+    )
+}
 
-Unfortunately we were not able to post your reply. The reason was: `{}`. Please contact the repository's owners for assistance.
+pub fn new_issue_failure(e: Error, lang: Option<&str>) -> Result<String> {
+    render(
+        "new_issue_failure",
+        lang,
+        context! { reason => e.to_string() },
+    )
+}
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>",  e, local_part = &conf.local_part, domain = &conf.domain, bot_name = &conf.bot_name)
+pub fn new_issue_success(
+    title: String,
+    password: Password,
+    issue_id: i64,
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    render(
+        "new_issue_success",
+        lang,
+        context! {
+            title,
+            password => password.to_string(),
+            url => issue_url(issue_id, conf),
+        },
+    )
+}
+
+pub fn new_reply_failure(e: Error, lang: Option<&str>) -> Result<String> {
+    render(
+        "new_reply_failure",
+        lang,
+        context! { reason => e.to_string() },
+    )
 }
 
 pub fn new_reply_success(
@@ -62,146 +607,545 @@ pub fn new_reply_success(
     password: Password,
     issue_id: i64,
     is_subscribed: bool,
+    lang: Option<&str>,
     conf: &Configuration,
-) -> String {
-    if is_subscribed {
-        format!("Hello,
-
-Your reply to issue \"{title}\" has been successfully posted. You can view the discussion here:
-
-{url}/{issue_id}
-
-You will receive replies from other users. To unsubscribe from the conversation, send an email to {local_part}+{password}+unsubscribe@{domain}.
-
-To reply to other users or post new comments, send your text to {local_part}+{password}+reply@{domain}.
-
-To close the issue, send an email to {local_part}+{password}+close@{domain}.
+) -> Result<String> {
+    render(
+        "new_reply_success",
+        lang,
+        context! {
+            title,
+            password => password.to_string(),
+            url => issue_url(issue_id, conf),
+            subscribed => is_subscribed,
+        },
+    )
+}
 
-Please keep this email in order to be able to keep in touch with your issue.
+pub fn close_success(
+    title: String,
+    issue_id: i64,
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    render(
+        "close_success",
+        lang,
+        context! { title, url => issue_url(issue_id, conf) },
+    )
+}
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>", title = title, password = password, issue_id = issue_id, url = BASE_ISSUE_URL.replace("{base_url}", &conf.base_url).replace("{repo}", &conf.repo), local_part = &conf.local_part, domain = &conf.domain, bot_name = &conf.bot_name)
-    } else {
-        format!("Hello,
+pub fn close_failure(e: Error, lang: Option<&str>) -> Result<String> {
+    render("close_failure", lang, context! { reason => e.to_string() })
+}
 
-Your reply to issue \"{title}\" has been successfully posted. You can view the discussion here:
+pub fn invalid_request(lang: Option<&str>) -> Result<String> {
+    render(
+        "invalid_request",
+        lang,
+        context! {
+            kinds => EventKind::join_list(&EventKind::ALL).replace(' ', ", "),
+            modes => delivery_modes(),
+        },
+    )
+}
 
-{url}/{issue_id}
+pub fn change_subscription_success(
+    title: String,
+    password: Password,
+    issue_id: i64,
+    is_subscribed: bool,
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    render(
+        "change_subscription_success",
+        lang,
+        context! {
+            title,
+            password => password.to_string(),
+            url => issue_url(issue_id, conf),
+            subscribed => is_subscribed,
+        },
+    )
+}
 
-You will not receive replies from other users. To subscribe to the conversation, send an email to {local_part}+{password}+subscribe@{domain}.
+pub fn change_subscription_failure(is_subscribed: bool, lang: Option<&str>) -> Result<String> {
+    render(
+        "change_subscription_failure",
+        lang,
+        context! { unsubscribed => is_subscribed },
+    )
+}
 
-To reply to other users or post new comments, send your text to {local_part}+{password}+reply@{domain}.
+pub fn event(event: &Event, lang: Option<&str>) -> Result<String> {
+    let (mut closed, mut label, mut added, mut assignee, mut removed, mut milestone) =
+        (None, None, None, None, None, None);
+    let user = match event {
+        Event::State { user, closed: c } => {
+            closed = Some(*c);
+            user
+        }
+        Event::Label {
+            user,
+            label: l,
+            added: a,
+        } => {
+            label = Some(l);
+            added = Some(*a);
+            user
+        }
+        Event::Assignee {
+            user,
+            assignee: a,
+            removed: r,
+        } => {
+            assignee = Some(a);
+            removed = Some(*r);
+            user
+        }
+        Event::Milestone { user, milestone: m } => {
+            milestone = m.as_ref();
+            user
+        }
+    };
+    render(
+        "event",
+        lang,
+        context! {
+            kind => event.kind().as_str(),
+            user,
+            closed,
+            label,
+            added,
+            assignee,
+            removed,
+            milestone,
+        },
+    )
+}
 
-To close the issue, send an email to {local_part}+{password}+close@{domain}.
+pub fn event_section(kind: EventKind, events: &[&Event], lang: Option<&str>) -> Result<String> {
+    let events = events
+        .iter()
+        .map(|e| event(e, lang))
+        .collect::<Result<Vec<String>>>()?;
+    Ok(render(
+        "event_section",
+        lang,
+        context! { kind => kind.as_str(), events },
+    )?
+    .trim_end()
+    .to_string())
+}
 
-Please keep this email in order to be able to keep in touch with your issue.
+/// A comment in an update notice.
+pub struct Comment {
+    /// The issue it was posted on, which links to a `#fragment` point into.
+    pub issue_id: i64,
+    /// `None` for comments posted by the bot, which already say who wrote them.
+    pub author: Option<String>,
+    /// The comment's Markdown.
+    pub body: String,
+}
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>", title = title, password = password, issue_id = issue_id, url = BASE_ISSUE_URL.replace("{base_url}", &conf.base_url).replace("{repo}", &conf.repo), local_part = &conf.local_part, domain = &conf.domain, bot_name = &conf.bot_name)
+impl Comment {
+    fn text(&self, lang: Option<&str>, conf: &Configuration) -> Result<String> {
+        render(
+            "comment",
+            lang,
+            context! {
+                author => self.author,
+                body => markdown::to_text(&self.body, self.issue_id, conf),
+            },
+        )
     }
 }
 
-pub fn close_success(title: String, issue_id: i64, conf: &Configuration) -> String {
-    format!(
-        "Hello,
-
-Your issue \"{title}\" has been successfully closed. You can view the discussion here:
-
-{url}/{issue_id}
+/// The changes and comments of one issue, as shown in update notices and digests.
+pub fn update_sections(
+    events: &[&Event],
+    comments: &[Comment],
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    let mut sections = vec![];
+    for kind in EventKind::ALL {
+        let events = events
+            .iter()
+            .copied()
+            .filter(|e| e.kind() == kind)
+            .collect::<Vec<&Event>>();
+        if !events.is_empty() {
+            sections.push(event_section(kind, &events, lang)?);
+        }
+    }
+    for comment in comments {
+        sections.push(comment.text(lang, conf)?);
+    }
+    Ok(sections
+        .join("\n\n-------------------------------------------------------------------------\n\n"))
+}
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>",
-        title = title,
-        issue_id = issue_id,
-        url = BASE_ISSUE_URL
-            .replace("{base_url}", &conf.base_url)
-            .replace("{repo}", &conf.repo),
-        local_part = &conf.local_part,
-        domain = &conf.domain,
-        bot_name = &conf.bot_name,
+/// [`update_sections`] for the HTML part, with the Markdown of comments rendered.
+pub fn update_sections_html(
+    events: &[&Event],
+    comments: &[Comment],
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    let mut sections = vec![];
+    for kind in EventKind::ALL {
+        let events = events
+            .iter()
+            .filter(|e| e.kind() == kind)
+            .map(|e| event(e, lang))
+            .collect::<Result<Vec<String>>>()?;
+        if !events.is_empty() {
+            sections.push(context! { kind => kind.as_str(), events });
+        }
+    }
+    let comments = comments
+        .iter()
+        .map(|c| {
+            context! {
+                author => c.author,
+                html => Value::from_safe_string(markdown::to_html(&c.body, c.issue_id, conf)),
+            }
+        })
+        .collect::<Vec<Value>>();
+    render(
+        "update_sections.html",
+        lang,
+        context! { sections, comments },
     )
 }
 
-pub fn close_failure(e: Error, conf: &Configuration) -> String {
-    format!("Hello,
-
-Unfortunately we were not able to close this issue. The reason was: `{}`. Please contact the repository's owners for assistance.
-
-This is an automated email from {bot_name} <{local_part}+help@{domain}>",  e, local_part = &conf.local_part, domain = &conf.domain, bot_name = &conf.bot_name)
+/// `html` is whether the subscriber also receives [`reply_update_html`], to tell them how to
+/// switch formats.
+pub fn reply_update(
+    issue: &Issue,
+    subscriber: &db::Subscriber,
+    lang: Option<&str>,
+    conf: &Configuration,
+    events: &[&Event],
+    comments: &[Comment],
+    html: bool,
+) -> Result<String> {
+    assert!(!(comments.is_empty() && events.is_empty()));
+    render(
+        "reply_update",
+        lang,
+        context! {
+            title => &issue.title,
+            password => subscriber.password.to_string(),
+            changes => !events.is_empty(),
+            kinds => EventKind::join_list(&EventKind::ALL).replace(' ', ", "),
+            modes => delivery_modes(),
+            sections => update_sections(events, comments, lang, conf)?,
+            html,
+        },
+    )
 }
 
-pub fn invalid_request(conf: &Configuration) -> String {
-    format!(
-        "Hello,
-
-Your request was not correct. Here are the valid requests you can ask from this bot:
-
-- post a new issue eponymously: send an e-mail with the issue title as the subject and the issue body as the email body to {local_part}@{domain}. On success a password will be given that allows you to reply, close the issue, and also change your subscription to the discussion.
-- post a new issue anonymously: send an email as above to the address {local_part}+anonymous@{domain}. {bot_name} will replace your name with 'Anonymous'
+pub fn reply_update_html(
+    issue: &Issue,
+    subscriber: &db::Subscriber,
+    lang: Option<&str>,
+    conf: &Configuration,
+    events: &[&Event],
+    comments: &[Comment],
+) -> Result<String> {
+    assert!(!(comments.is_empty() && events.is_empty()));
+    render(
+        "reply_update.html",
+        lang,
+        context! {
+            title => &issue.title,
+            password => subscriber.password.to_string(),
+            changes => !events.is_empty(),
+            kinds => EventKind::join_list(&EventKind::ALL).replace(' ', ", "),
+            modes => delivery_modes(),
+            sections => Value::from_safe_string(update_sections_html(events, comments, lang, conf)?),
+        },
+    )
+}
 
-If p is the given password, you may perform actions on your issue as follows:
+fn delivery_modes() -> String {
+    Delivery::ALL
+        .iter()
+        .map(|d| d.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
 
-- reply: {local_part}+p+reply@{domain}. Subject value can be anything.
-- close issue: {local_part}+p+close@{domain} email content can be anything
-- change subscription: {local_part}+p+unsubscribe@{domain} and {local_part}+p+subscribe@{domain}
+pub fn digest(
+    lang: Option<&str>,
+    conf: &Configuration,
+    issues: &[(i64, String, Password, Vec<String>)],
+) -> Result<String> {
+    let issues = issues
+        .iter()
+        .map(|(issue_id, title, password, updates)| {
+            context! {
+                title,
+                url => issue_url(*issue_id, conf),
+                password => password.to_string(),
+                updates => updates.join(
+                    "\n\n-------------------------------------------------------------------------\n\n"
+                ),
+            }
+        })
+        .collect::<Vec<Value>>();
+    render(
+        "digest",
+        lang,
+        context! { issues, modes => delivery_modes() },
+    )
+}
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>",
-        local_part = &conf.local_part,
-        domain = &conf.domain,
-        bot_name = &conf.bot_name,
+/// [`digest`] for the HTML part, from the [`update_sections_html`] of each issue.
+pub fn digest_html(
+    lang: Option<&str>,
+    conf: &Configuration,
+    issues: &[(i64, String, Password, Vec<String>)],
+) -> Result<String> {
+    let issues = issues
+        .iter()
+        .map(|(issue_id, title, password, updates)| {
+            context! {
+                title,
+                url => issue_url(*issue_id, conf),
+                password => password.to_string(),
+                updates => updates
+                    .iter()
+                    .map(|u| Value::from_safe_string(u.clone()))
+                    .collect::<Vec<Value>>(),
+            }
+        })
+        .collect::<Vec<Value>>();
+    render(
+        "digest.html",
+        lang,
+        context! { issues, modes => delivery_modes() },
     )
 }
 
-pub fn change_subscription_success(
+pub fn change_delivery_success(
     title: String,
     password: Password,
     issue_id: i64,
-    is_subscribed: bool,
+    delivery: Delivery,
+    lang: Option<&str>,
     conf: &Configuration,
-) -> String {
-    format!("Hello,
+) -> Result<String> {
+    render(
+        "change_delivery_success",
+        lang,
+        context! {
+            title,
+            password => password.to_string(),
+            url => issue_url(issue_id, conf),
+            delivery => delivery.as_str(),
+            modes => delivery_modes(),
+        },
+    )
+}
 
-Your subscription change to issue \"{title}\" has been successfully performed. You can view the discussion here:
+pub fn change_delivery_failure(e: Error, lang: Option<&str>) -> Result<String> {
+    render(
+        "change_delivery_failure",
+        lang,
+        context! { reason => e.to_string() },
+    )
+}
 
-{url}/{issue_id}
+pub fn change_format_success(
+    title: String,
+    password: Password,
+    issue_id: i64,
+    html: bool,
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    render(
+        "change_format_success",
+        lang,
+        context! {
+            title,
+            password => password.to_string(),
+            url => issue_url(issue_id, conf),
+            html,
+        },
+    )
+}
 
-You will {not}receive replies from other users. To {un}subscribe to the conversation, send an email to {local_part}+{password}+{un}subscribe@{domain}.
+pub fn change_format_failure(e: Error, lang: Option<&str>) -> Result<String> {
+    render(
+        "change_format_failure",
+        lang,
+        context! { reason => e.to_string() },
+    )
+}
 
-To reply to other users or post new comments, send your text to {local_part}+{password}+reply@{domain}.
+pub fn change_muted_success(
+    title: String,
+    password: Password,
+    issue_id: i64,
+    kind: EventKind,
+    mute: bool,
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    render(
+        "change_muted_success",
+        lang,
+        context! {
+            title,
+            password => password.to_string(),
+            url => issue_url(issue_id, conf),
+            kind => kind.as_str(),
+            mute,
+        },
+    )
+}
 
-To close the issue, send an email to {local_part}+{password}+close@{domain}.
+pub fn change_muted_failure(e: Error, lang: Option<&str>) -> Result<String> {
+    render(
+        "change_muted_failure",
+        lang,
+        context! { reason => e.to_string() },
+    )
+}
 
-Please keep this email in order to be able to keep in touch with your issue.
+pub fn test_mail(lang: Option<&str>) -> Result<String> {
+    render("test_mail", lang, context! {})
+}
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>", title = title, password = password, issue_id = issue_id, url = BASE_ISSUE_URL.replace("{base_url}", &conf.base_url).replace("{repo}", &conf.repo), local_part = &conf.local_part, domain = &conf.domain, bot_name = &conf.bot_name, not = if is_subscribed { "" }else {"not "}, un = if is_subscribed { "un" } else { "" } )
+pub fn subscription_request(
+    title: &str,
+    password: Password,
+    issue_id: i64,
+    invited_by: Option<&Address>,
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    render(
+        "subscription_request",
+        lang,
+        context! {
+            title,
+            password => password.to_string(),
+            url => issue_url(issue_id, conf),
+            invited_by => invited_by.map(|a| a.to_string()),
+        },
+    )
 }
 
-pub fn change_subscription_failure(is_subscribed: bool, conf: &Configuration) -> String {
-    format!(
-        "Hello,
+pub fn subscription_request_failure(e: Error, lang: Option<&str>) -> Result<String> {
+    render(
+        "subscription_request_failure",
+        lang,
+        context! { reason => e.to_string() },
+    )
+}
 
-Your subscription change was unsuccessful. You are already {un}subscribed.
+pub fn confirm_subscription_success(
+    title: String,
+    password: Password,
+    issue_id: i64,
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    render(
+        "confirm_subscription_success",
+        lang,
+        context! {
+            title,
+            password => password.to_string(),
+            url => issue_url(issue_id, conf),
+        },
+    )
+}
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>",
-        local_part = &conf.local_part,
-        domain = &conf.domain,
-        bot_name = &conf.bot_name,
-        un = if is_subscribed { "un" } else { "" }
+pub fn import_success(
+    title: &str,
+    password: Password,
+    issue_id: i64,
+    anonymous: bool,
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    render(
+        "import_success",
+        lang,
+        context! {
+            title,
+            password => password.to_string(),
+            url => issue_url(issue_id, conf),
+            anonymous,
+        },
     )
 }
 
-pub fn reply_update(issue: &Issue, conf: &Configuration, comments: Vec<String>) -> String {
-    assert!(!comments.is_empty());
-    format!(
-        "Hello,
+pub fn forget_success(
+    erased: &erase::Erased,
+    lang: Option<&str>,
+    conf: &Configuration,
+) -> Result<String> {
+    let issues = erased
+        .issues
+        .iter()
+        .map(|(id, title)| context! { title, url => issue_url(*id, conf) })
+        .collect::<Vec<_>>();
+    render(
+        "forget_success",
+        lang,
+        context! {
+            issues,
+            subscriptions => erased.subscriptions,
+            posts => erased.posts,
+            failed => erased.failed,
+        },
+    )
+}
 
-There have been new replies in issue `{title}`. You are receiving this notice because you are subscribed to the discussion. To unsubscribe, send an email to {local_part}+{password}+unsubscribe@{domain}
+pub fn forget_failure(e: Error, lang: Option<&str>) -> Result<String> {
+    render("forget_failure", lang, context! { reason => e.to_string() })
+}
 
-{comments}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-This is an automated email from {bot_name} <{local_part}+help@{domain}>",
-        local_part = &conf.local_part,
-        domain = &conf.domain,
-        bot_name = &conf.bot_name,
-        password = &issue.password.to_string(),
-        title = &issue.title,
-        comments = comments.join("\n\n-------------------------------------------------------------------------\n\n")
-    )
+    #[test]
+    fn builtin_catalogs() {
+        let conf = conf::example();
+        // Loading renders every template of every language with example values.
+        load(&conf).unwrap();
+        let subject = |lang| {
+            subject(
+                "reply_update",
+                context! { title => "Crash", replies => true },
+                lang,
+                &conf,
+            )
+            .unwrap()
+        };
+        assert_eq!(subject(None), "[meli-issues] new replies in issue `Crash`");
+        assert_eq!(
+            subject(Some("de")),
+            "[meli-issues] Neue Antworten im Issue `Crash`"
+        );
+        assert_eq!(
+            subject(Some("es")),
+            "[meli-issues] Nuevas respuestas en la incidencia `Crash`"
+        );
+        assert_eq!(
+            subject(Some("el")),
+            "[meli-issues] Νέες απαντήσεις στο θέμα `Crash`"
+        );
+        // Unknown languages fall back to the default one.
+        assert_eq!(subject(Some("fr")), subject(None));
+    }
 }
@@ -0,0 +1,182 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Handing finalised messages to the mail system, either by piping them to a sendmail-like
+//! command or by submitting them to an SMTP server.
+
+use super::*;
+use melib::smtp::{SmtpConnection, SmtpServerConf};
+
+pub enum Transport<'a> {
+    Sendmail(&'a str),
+    Smtp(&'a SmtpServerConf),
+}
+
+/// Split a command line into words like a POSIX shell would, honouring single quotes, double
+/// quotes and backslash escapes. No expansions are performed.
+pub fn split_command(command: &str) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(Error::new("Unterminated ' in mailer command.")),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(Error::new("Unterminated \" in mailer command.")),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(Error::new("Unterminated \" in mailer command.")),
+                    }
+                }
+            }
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    word.get_or_insert_with(String::new).push(c);
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    if words.is_empty() {
+        return Err(Error::new("mailer command is empty."));
+    }
+    Ok(words)
+}
+
+fn sendmail(command: &str, message: &str) -> Result<()> {
+    use std::io::Write;
+    use std::process::Stdio;
+    let parts = split_command(command)?;
+    let (cmd, args) = (&parts[0], &parts[1..]);
+    let mut mailer = std::process::Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    {
+        let stdin = mailer.stdin.as_mut().expect("failed to open stdin");
+        stdin.write_all(message.as_bytes())?;
+    }
+    let output = mailer.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::new(format!(
+            "Mailer failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Connect, authenticate and submit `message`. The envelope recipients are taken from the
+/// message's `To`, `Cc` and `Bcc` headers; the envelope sender is `envelope_from`, or the
+/// `From` header when that's empty. Server rejections are returned with the server's reply.
+fn smtp(server: &SmtpServerConf, message: &str) -> Result<()> {
+    smol::block_on(async {
+        let mut connection = SmtpConnection::new_connection(server.clone()).await?;
+        connection.mail_transaction(message, None).await
+    })?;
+    Ok(())
+}
+
+/// Connect to the SMTP server and authenticate without sending anything.
+pub fn smtp_probe(server: &SmtpServerConf) -> Result<()> {
+    smol::block_on(SmtpConnection::new_connection(server.clone()))?;
+    Ok(())
+}
+
+pub fn deliver(message: &str, conf: &Configuration) -> Result<()> {
+    match conf.transport()? {
+        Transport::Sendmail(command) => sendmail(command, message),
+        Transport::Smtp(server) => smtp(server, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(command: &str) -> Vec<String> {
+        split_command(command).unwrap()
+    }
+
+    #[test]
+    fn split_command_words() {
+        assert_eq!(split("  sendmail   -t  "), ["sendmail", "-t"]);
+        assert_eq!(
+            split("/usr/sbin/sendmail -t webmaster@meli.delivery"),
+            ["/usr/sbin/sendmail", "-t", "webmaster@meli.delivery"]
+        );
+    }
+
+    #[test]
+    fn split_command_quotes() {
+        assert_eq!(split(r#"cmd 'a b' "c d""#), ["cmd", "a b", "c d"]);
+        assert_eq!(
+            split(r#"cmd 'a "b"' "c 'd'""#),
+            ["cmd", r#"a "b""#, "c 'd'"]
+        );
+        assert_eq!(split(r#"cmd a'b'"c"d"#), ["cmd", "abcd"]);
+        assert_eq!(split("cmd '' \"\""), ["cmd", "", ""]);
+        // No expansions inside quotes.
+        assert_eq!(split(r#"cmd '$HOME' "~""#), ["cmd", "$HOME", "~"]);
+    }
+
+    #[test]
+    fn split_command_escapes() {
+        assert_eq!(split(r"cmd a\ b \'c"), ["cmd", "a b", "'c"]);
+        assert_eq!(
+            split(r#"cmd "a\"b" "\$x" "\n""#),
+            ["cmd", r#"a"b"#, "$x", r"\n"]
+        );
+        assert_eq!(split(r"cmd 'a\b'"), ["cmd", r"a\b"]);
+    }
+
+    #[test]
+    fn split_command_errors() {
+        assert!(split_command("").is_err());
+        assert!(split_command("   ").is_err());
+        assert!(split_command("cmd 'a").is_err());
+        assert!(split_command("cmd \"a").is_err());
+        assert!(split_command("cmd \"a\\").is_err());
+    }
+}
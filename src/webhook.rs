@@ -0,0 +1,367 @@
+/* This file is part of issue-bot.
+ *
+ * issue-bot is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * issue-bot is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with issue-bot.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A minimal HTTP listener for Gitea webhooks.
+//!
+//! Deliveries for tracked issues trigger the same check `cron` performs, so notices go out as soon
+//! as something happens. Polling with `cron` still catches anything a failed delivery missed.
+//!
+//! The listener also serves the RFC 8058 one-click unsubscription URL of the `List-Unsubscribe`
+//! header of notices.
+
+use super::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+static WEBHOOK_PATH: &str = "/webhook";
+pub static UNSUBSCRIBE_PATH: &str = "/unsubscribe";
+
+/// Largest request body we accept. A single Gitea event payload is a few KiB.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const MAX_HEADERS: usize = 100;
+/// Connections handled at the same time, so that a slow client doesn't hold up deliveries.
+const WORKERS: usize = 4;
+/// How long a client may take to send its request or read the response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The value of parameter `name` in the query string. Values are not percent-decoded, we
+    /// only pass passwords.
+    fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v)
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request> {
+    use std::io::Read;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(Error::new("Malformed request line.")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let mut request = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers: vec![],
+        body: vec![],
+    };
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::new("Connection closed before end of headers."));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if request.headers.len() == MAX_HEADERS {
+            return Err(Error::new("Too many headers."));
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| Error::new(format!("Malformed header: {:?}", header)))?;
+        request
+            .headers
+            .push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let length = match request.header("Content-Length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| Error::new("Invalid Content-Length."))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(Error::new(format!("Request body too large: {}", length)));
+    }
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body)?;
+    Ok(request)
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    respond_with(stream, status, "text/plain", body)
+}
+
+fn respond_with(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// A 204 response has neither a body nor a `Content-Length`.
+fn respond_no_content(stream: &mut TcpStream) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Check Gitea's `X-Gitea-Signature`, the hex encoded HMAC-SHA256 of the body.
+fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature.trim()) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Check the issue `event` is about. The database is only locked to look the issue up and to
+/// record the activity, not while it is fetched from Gitea, so that other connections aren't
+/// held up by a slow Gitea.
+fn handle_event(
+    conn: &Mutex<Connection>,
+    conf: &Configuration,
+    event: &str,
+    body: &[u8],
+) -> Result<()> {
+    if !matches!(event, "issues" | "issue_comment") {
+        trace!("Ignoring webhook event {:?}.", event);
+        return Ok(());
+    }
+    let payload: serde_json::Value = serde_json::from_slice(body)?;
+    let repository = &payload["repository"];
+    if repository["full_name"].as_str() != Some(conf.repo.as_str()) {
+        info!(
+            "Ignoring webhook event for another repository: {}",
+            repository["full_name"]
+        );
+        return Ok(());
+    }
+    let issue = &payload["issue"];
+    let number = gitea_api_mismatch!(issue["number"].as_i64());
+    let issue = db::issue(&conn.lock().unwrap(), number)?;
+    match issue {
+        Some(issue) => {
+            info!("Webhook event {} for issue {}.", event, number);
+            let activity = cron::fetch_activity(&issue, conf)?;
+            // If another thread recorded the same activity in the meantime, this is a no-op.
+            cron::process_activity(&conn.lock().unwrap(), conf, issue, activity)?;
+        }
+        None => trace!("Ignoring webhook event for untracked issue {}.", number),
+    }
+    Ok(())
+}
+
+fn handle_connection(
+    conn: &Mutex<Connection>,
+    conf: &Configuration,
+    webhook: &WebhookConfiguration,
+    stream: &mut TcpStream,
+) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let request = match read_request(stream) {
+        Ok(request) => request,
+        Err(err) => {
+            respond(stream, "400 Bad Request", "bad request\n")?;
+            return Err(err);
+        }
+    };
+    if request.path == UNSUBSCRIBE_PATH {
+        return unsubscribe(&conn.lock().unwrap(), conf, &request, stream);
+    }
+    if request.path != WEBHOOK_PATH {
+        return respond(stream, "404 Not Found", "not found\n");
+    }
+    if request.method != "POST" {
+        return respond(stream, "405 Method Not Allowed", "method not allowed\n");
+    }
+    let verified = request
+        .header("X-Gitea-Signature")
+        .map(|signature| verify_signature(&webhook.secret, &request.body, signature))
+        .unwrap_or(false);
+    if !verified {
+        respond(stream, "401 Unauthorized", "invalid signature\n")?;
+        return Err(Error::new(format!(
+            "Rejected webhook delivery with invalid signature from {:?}.",
+            stream.peer_addr()
+        )));
+    }
+    let event = request.header("X-Gitea-Event").unwrap_or_default();
+    // Reply before doing any work: Gitea gives up on deliveries that take more than a few seconds.
+    respond_no_content(stream)?;
+    _ = stream.shutdown(Shutdown::Both);
+    handle_event(conn, conf, event, &request.body)
+}
+
+/// One-click unsubscription. Only a POST with the RFC 8058 body unsubscribes: mail scanners and
+/// link previews GET the URL, which just shows a button that POSTs.
+fn unsubscribe(
+    conn: &Connection,
+    conf: &Configuration,
+    request: &Request,
+    stream: &mut TcpStream,
+) -> Result<()> {
+    let Some(password) = request
+        .query_param("password")
+        .and_then(|p| Password::parse_str(p).ok())
+    else {
+        return respond(stream, "400 Bad Request", "bad request\n");
+    };
+    match request.method.as_str() {
+        "GET" => {
+            return respond_with(
+                stream,
+                "200 OK",
+                "text/html",
+                "<!DOCTYPE html>\n<form method=\"post\"><button name=\"List-Unsubscribe\" value=\"One-Click\">Unsubscribe</button></form>\n",
+            )
+        }
+        "POST" => {}
+        _ => return respond(stream, "405 Method Not Allowed", "method not allowed\n"),
+    }
+    let one_click = String::from_utf8_lossy(&request.body)
+        .split('&')
+        .any(|p| p.trim() == "List-Unsubscribe=One-Click");
+    if !one_click {
+        return respond(stream, "400 Bad Request", "bad request\n");
+    }
+    let token = match db::find_token(conn, password) {
+        Ok(token) => token,
+        Err(_) => return respond(stream, "404 Not Found", "not found\n"),
+    };
+    if !token.subscribed {
+        return respond(stream, "200 OK", "unsubscribed\n");
+    }
+    if conf.dry_run {
+        eprintln!(
+            "DRY_RUN: NOT unsubscribing {} from issue {}",
+            token.address, token.issue_id
+        );
+    } else {
+        api::change_subscription(conn, password, false)?;
+        info!(
+            "{} unsubscribed from issue {} with one click.",
+            token.address, token.issue_id
+        );
+    }
+    respond(stream, "200 OK", "unsubscribed\n")
+}
+
+/// Listen for webhook deliveries and unsubscriptions. Requests are read by a pool of `WORKERS`
+/// threads, which take turns with the database connection.
+pub fn serve(conn: Connection, conf: Configuration) -> Result<()> {
+    let webhook = conf.webhook.as_ref().ok_or_else(|| {
+        Error::new("`serve` requires a [webhook] section in the configuration file.")
+    })?;
+    let listener = TcpListener::bind(&webhook.listen)?;
+    info!("Listening for Gitea webhooks on {}.", &webhook.listen);
+    let conn = Mutex::new(conn);
+    let (tx, rx) = mpsc::channel::<TcpStream>();
+    let rx = Mutex::new(rx);
+    std::thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            let (conn, conf, rx) = (&conn, &conf, &rx);
+            scope.spawn(move || loop {
+                let Ok(mut stream) = rx.lock().unwrap().recv() else {
+                    break;
+                };
+                if let Err(err) = handle_connection(conn, conf, webhook, &mut stream) {
+                    error!("Webhook error: {}", err);
+                }
+            });
+        }
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if tx.send(stream).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => error!("Could not accept connection: {}", err),
+            }
+        }
+        drop(tx);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"action":"opened"}"#;
+    /// HMAC-SHA256 of `BODY` with the key `secret`.
+    const SIGNATURE: &str = "d42142b53efbc7cf5cd20b6e074eb33707e0de3b368f698e6d6f6c824ffb8d37";
+
+    #[test]
+    fn valid_signature() {
+        assert!(verify_signature("secret", BODY, SIGNATURE));
+        assert!(verify_signature(
+            "secret",
+            BODY,
+            &format!(" {}\n", SIGNATURE.to_uppercase())
+        ));
+    }
+
+    #[test]
+    fn invalid_signature() {
+        assert!(!verify_signature("other secret", BODY, SIGNATURE));
+        assert!(!verify_signature(
+            "secret",
+            br#"{"action":"closed"}"#,
+            SIGNATURE
+        ));
+        assert!(!verify_signature("secret", BODY, &SIGNATURE[..62]));
+        assert!(!verify_signature("secret", BODY, "not hex"));
+        assert!(!verify_signature("secret", BODY, ""));
+    }
+}